@@ -0,0 +1,374 @@
+use std::{borrow::Cow, collections::HashMap, fmt, fs::File, path::Path};
+use memmap2::Mmap;
+use serde::{Serialize, de::DeserializeOwned};
+use safetensors::tensor::{Dtype, SafeTensorError, SafeTensors, TensorView, View, serialize_to_file};
+use tch::{Device, Kind, TchError, Tensor, nn::VarStore};
+use crate::modules::{Module, StateDict, StateDictError, StateDictKeys, match_state_dict};
+
+/// Metadata stored in the header of a checkpoint
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CheckpointMetadata {
+    /// The model config, as JSON
+    pub config: Option<String>,
+    /// The training step the checkpoint was saved at
+    pub step: Option<u64>,
+    /// Any other string metadata
+    pub extra: HashMap<String, String>,
+}
+
+impl CheckpointMetadata {
+    /// Metadata storing a model config (ex. LanguageModelConfig) as JSON
+    pub fn with_config<C: Serialize>(config: &C) -> Result<Self, CheckpointError> {
+        Ok(CheckpointMetadata {
+            config: Some(serde_json::to_string(config)?),
+            ..Default::default()
+        })
+    }
+
+    /// Parse the stored model config, if there is one
+    pub fn parse_config<C: DeserializeOwned>(&self) -> Result<Option<C>, CheckpointError> {
+        Ok(match &self.config {
+            Some(config) => Some(serde_json::from_str(config)?),
+            None => None,
+        })
+    }
+
+    fn to_map(&self) -> HashMap<String, String> {
+        let mut map = self.extra.clone();
+        if let Some(config) = &self.config {
+            map.insert("config".to_string(), config.clone());
+        }
+        if let Some(step) = self.step {
+            map.insert("step".to_string(), step.to_string());
+        }
+        map
+    }
+
+    fn from_map(mut map: HashMap<String, String>) -> Self {
+        let config = map.remove("config");
+        let step = map.get("step").and_then(|step| step.parse().ok());
+        if step.is_some() {
+            map.remove("step");
+        }
+        CheckpointMetadata {
+            config,
+            step,
+            extra: map,
+        }
+    }
+}
+
+/// An error type for reading and writing checkpoints
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(std::io::Error),
+    SafeTensors(SafeTensorError),
+    /// The tensor kind can't be stored in safetensors
+    UnsupportedKind(Kind),
+    /// The safetensors dtype has no matching tensor kind
+    UnsupportedDtype(Dtype),
+    StateDict(StateDictError),
+    Json(serde_json::Error),
+    Tch(TchError),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "IO error: {}", e),
+            CheckpointError::SafeTensors(e) => write!(f, "Safetensors error: {:?}", e),
+            CheckpointError::UnsupportedKind(kind) => write!(f, "Unsupported tensor kind: {:?}", kind),
+            CheckpointError::UnsupportedDtype(dtype) => write!(f, "Unsupported safetensors dtype: {:?}", dtype),
+            CheckpointError::StateDict(e) => write!(f, "{}", e),
+            CheckpointError::Json(e) => write!(f, "JSON error: {}", e),
+            CheckpointError::Tch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(e: std::io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+impl From<SafeTensorError> for CheckpointError {
+    fn from(e: SafeTensorError) -> Self {
+        CheckpointError::SafeTensors(e)
+    }
+}
+
+impl From<StateDictError> for CheckpointError {
+    fn from(e: StateDictError) -> Self {
+        CheckpointError::StateDict(e)
+    }
+}
+
+impl From<serde_json::Error> for CheckpointError {
+    fn from(e: serde_json::Error) -> Self {
+        CheckpointError::Json(e)
+    }
+}
+
+impl From<TchError> for CheckpointError {
+    fn from(e: TchError) -> Self {
+        CheckpointError::Tch(e)
+    }
+}
+
+fn to_dtype(kind: Kind) -> Result<Dtype, CheckpointError> {
+    Ok(match kind {
+        Kind::Bool => Dtype::BOOL,
+        Kind::Uint8 => Dtype::U8,
+        Kind::Int8 => Dtype::I8,
+        Kind::Int16 => Dtype::I16,
+        Kind::Int => Dtype::I32,
+        Kind::Int64 => Dtype::I64,
+        Kind::Half => Dtype::F16,
+        Kind::BFloat16 => Dtype::BF16,
+        Kind::Float => Dtype::F32,
+        Kind::Double => Dtype::F64,
+        kind => return Err(CheckpointError::UnsupportedKind(kind)),
+    })
+}
+
+fn to_kind(dtype: Dtype) -> Result<Kind, CheckpointError> {
+    Ok(match dtype {
+        Dtype::BOOL => Kind::Bool,
+        Dtype::U8 => Kind::Uint8,
+        Dtype::I8 => Kind::Int8,
+        Dtype::I16 => Kind::Int16,
+        Dtype::I32 => Kind::Int,
+        Dtype::I64 => Kind::Int64,
+        Dtype::F16 => Kind::Half,
+        Dtype::BF16 => Kind::BFloat16,
+        Dtype::F32 => Kind::Float,
+        Dtype::F64 => Kind::Double,
+        dtype => return Err(CheckpointError::UnsupportedDtype(dtype)),
+    })
+}
+
+/// A tensor waiting to be written to a checkpoint, its data is only read (and copied to the CPU if needed) as the file is written
+struct TensorData<'a> {
+    tensor: &'a Tensor,
+    dtype: Dtype,
+    shape: Vec<usize>,
+}
+
+impl<'a> TensorData<'a> {
+    fn new(tensor: &'a Tensor) -> Result<Self, CheckpointError> {
+        Ok(TensorData {
+            tensor,
+            dtype: to_dtype(tensor.kind())?,
+            shape: tensor.size().iter().map(|s| *s as usize).collect(),
+        })
+    }
+}
+
+impl<'a> View for TensorData<'a> {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn data(&self) -> Cow<[u8]> {
+        let len = self.data_len();
+        if self.tensor.device() == Device::Cpu && self.tensor.is_contiguous() {
+            if len == 0 {
+                return Cow::Borrowed(&[]);
+            }
+            // The storage is borrowed from the tensor, which outlives the returned slice
+            Cow::Borrowed(unsafe { std::slice::from_raw_parts(self.tensor.data_ptr() as *const u8, len) })
+        } else {
+            let tensor = self.tensor.to_device(Device::Cpu).contiguous();
+            let mut bytes = vec![0u8; len];
+            tensor.copy_data_u8(&mut bytes, tensor.numel());
+            Cow::Owned(bytes)
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        self.shape.iter().product::<usize>() * self.tensor.kind().elt_size_in_bytes()
+    }
+}
+
+/// Save named tensors (a state dict, or VarStore::variables()) to a safetensors file
+pub fn save<T: AsRef<Path>>(tensors: &StateDict, metadata: &CheckpointMetadata, path: T) -> Result<(), CheckpointError> {
+    // Safetensors stores contiguous little endian data, tensors are written one at a time so at most one extra copy is held in memory
+    let views = tensors.iter()
+        .map(|(name, tensor)| Ok((name.as_str(), TensorData::new(tensor)?)))
+        .collect::<Result<Vec<_>, CheckpointError>>()?;
+    serialize_to_file(views, &Some(metadata.to_map()), path.as_ref())?;
+    Ok(())
+}
+
+/// Save a module's state dict to a safetensors file
+pub fn save_module<M: Module, T: AsRef<Path>>(module: &M, metadata: &CheckpointMetadata, path: T) -> Result<(), CheckpointError> {
+    save(&module.state_dict(), metadata, path)
+}
+
+/// Save all variables in a VarStore to a safetensors file
+pub fn save_varstore<T: AsRef<Path>>(vs: &VarStore, metadata: &CheckpointMetadata, path: T) -> Result<(), CheckpointError> {
+    save(&vs.variables(), metadata, path)
+}
+
+/// Save a model config as a JSON file (ex. config.json next to the weights)
+pub fn save_config<C: Serialize, T: AsRef<Path>>(config: &C, path: T) -> Result<(), CheckpointError> {
+    serde_json::to_writer_pretty(File::create(path)?, config)?;
+    Ok(())
+}
+
+/// Read a model config from a JSON file
+pub fn read_config<C: DeserializeOwned, T: AsRef<Path>>(path: T) -> Result<C, CheckpointError> {
+    Ok(serde_json::from_reader(std::io::BufReader::new(File::open(path)?))?)
+}
+
+fn map_file<T: AsRef<Path>>(path: T) -> Result<Mmap, CheckpointError> {
+    let file = File::open(path)?;
+    // The file must not be modified while it's mapped
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+fn read_header_metadata(buffer: &[u8]) -> Result<CheckpointMetadata, CheckpointError> {
+    let (_, metadata) = SafeTensors::read_metadata(buffer)?;
+    Ok(CheckpointMetadata::from_map(metadata.metadata().clone().unwrap_or_default()))
+}
+
+fn view_to_tensor(view: &TensorView) -> Result<Tensor, CheckpointError> {
+    let shape: Vec<i64> = view.shape().iter().map(|s| *s as i64).collect();
+    Ok(Tensor::of_data_size(view.data(), &shape, to_kind(view.dtype())?))
+}
+
+/// Read only the metadata from a safetensors file
+pub fn read_metadata<T: AsRef<Path>>(path: T) -> Result<CheckpointMetadata, CheckpointError> {
+    read_header_metadata(&map_file(path)?)
+}
+
+/// Read every tensor in a safetensors file onto the cpu
+pub fn read<T: AsRef<Path>>(path: T) -> Result<(StateDict, CheckpointMetadata), CheckpointError> {
+    let mmap = map_file(path)?;
+    let metadata = read_header_metadata(&mmap)?;
+    let state_dict = SafeTensors::deserialize(&mmap)?
+        .tensors()
+        .iter()
+        .map(|(name, view)| Ok((name.clone(), view_to_tensor(view)?)))
+        .collect::<Result<StateDict, CheckpointError>>()?;
+    Ok((state_dict, metadata))
+}
+
+/// Copy tensors from a memory mapped file into the parameters one at a time, so the checkpoint is never fully loaded into memory
+fn load_into<T: AsRef<Path>>(parameters: Vec<(String, Tensor)>, path: T, strict: bool) -> Result<(StateDictKeys, CheckpointMetadata), CheckpointError> {
+    let mmap = map_file(path)?;
+    let metadata = read_header_metadata(&mmap)?;
+    let tensors = SafeTensors::deserialize(&mmap)?;
+    let mut shapes = HashMap::new();
+    for (name, view) in tensors.tensors() {
+        to_kind(view.dtype())?;
+        shapes.insert(name, view.shape().iter().map(|s| *s as i64).collect());
+    }
+    let keys = match_state_dict(&parameters, &shapes, strict)?;
+    for (name, mut parameter) in parameters {
+        if shapes.contains_key(&name) {
+            let tensor = view_to_tensor(&tensors.tensor(&name)?)?;
+            tch::no_grad(|| parameter.copy_(&tensor));
+        }
+    }
+    Ok((keys, metadata))
+}
+
+/// Load a module's parameters and buffers from a safetensors file, see Module::load_state_dict for how keys are matched
+pub fn load_module<M: Module, T: AsRef<Path>>(module: &mut M, path: T, strict: bool) -> Result<(StateDictKeys, CheckpointMetadata), CheckpointError> {
+    let mut tensors = module.named_parameters();
+    tensors.extend(module.named_buffers());
+    load_into(tensors, path, strict)
+}
+
+/// Load the variables of a VarStore from a safetensors file
+pub fn load_varstore<T: AsRef<Path>>(vs: &VarStore, path: T, strict: bool) -> Result<(StateDictKeys, CheckpointMetadata), CheckpointError> {
+    load_into(vs.variables().into_iter().collect(), path, strict)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use tch::{Device, Kind, Tensor, nn};
+    use crate::modules::Linear;
+    use super::{CheckpointMetadata, load_module, load_varstore, read, read_metadata, save, save_module, save_varstore};
+
+    /// A file in the temp directory, unique to this test and process, which is removed when dropped (even if the test fails)
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            TempFile(std::env::temp_dir().join(format!("condor_test_{}_{}.safetensors", name, std::process::id())))
+        }
+    }
+
+    impl AsRef<Path> for TempFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_module_roundtrip() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let source = Linear::new(&(&vs.root() / "source"), 10, 5);
+        let mut target = Linear::new(&(&vs.root() / "target"), 10, 5);
+        let metadata = CheckpointMetadata {
+            config: Some("{\"in_dim\": 10, \"out_dim\": 5}".to_string()),
+            step: Some(1200),
+            ..Default::default()
+        };
+        let path = TempFile::new("module");
+        save_module(&source, &metadata, &path).unwrap();
+        assert_eq!(read_metadata(&path).unwrap(), metadata);
+        let (keys, loaded_metadata) = load_module(&mut target, &path, true).unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(loaded_metadata, metadata);
+        assert!(target.ws.equal(&source.ws));
+        assert!(target.bs.equal(&source.bs));
+
+        // A mismatched module can't be loaded
+        let mut wrong = Linear::new(&(&vs.root() / "wrong"), 10, 6);
+        save_module(&source, &metadata, &path).unwrap();
+        assert!(load_module(&mut wrong, &path, false).is_err());
+    }
+
+    #[test]
+    fn test_varstore_and_kinds() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = Linear::new(&(&vs.root() / "linear"), 4, 3);
+        let path = TempFile::new("varstore");
+        save_varstore(&vs, &CheckpointMetadata::default(), &path).unwrap();
+        let other_vs = nn::VarStore::new(Device::Cpu);
+        let _ = Linear::new(&(&other_vs.root() / "linear"), 4, 3);
+        assert!(load_varstore(&other_vs, &path, true).unwrap().0.is_empty());
+        assert!(other_vs.variables()["linear.weight"].equal(&vs.variables()["linear.weight"]));
+
+        // Integer and boolean tensors survive a roundtrip
+        let mut tensors = crate::modules::StateDict::new();
+        tensors.insert("ints".to_string(), Tensor::of_slice(&[1i64, -2, 3]).view([3, 1]));
+        tensors.insert("mask".to_string(), Tensor::of_slice(&[true, false]));
+        // Non-contiguous tensors are copied as they're written
+        tensors.insert("transposed".to_string(), Tensor::of_slice(&[1f32, 2., 3., 4., 5., 6.]).view([2, 3]).tr());
+        save(&tensors, &CheckpointMetadata::default(), &path).unwrap();
+        let (loaded, _) = read(&path).unwrap();
+        assert_eq!(loaded["ints"].size(), vec![3, 1]);
+        assert_eq!(loaded["ints"].kind(), Kind::Int64);
+        assert!(loaded["ints"].equal(&tensors["ints"]));
+        assert!(loaded["mask"].equal(&tensors["mask"]));
+        assert!(loaded["transposed"].equal(&tensors["transposed"]));
+    }
+}
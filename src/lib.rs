@@ -4,6 +4,12 @@ pub mod modules;
 /// Common utilities for machine learning
 pub mod utils;
 
+/// Sampling utilities for autoregressive generation
+pub mod sampling;
+
+/// Reading and writing checkpoints in the safetensors format
+pub mod checkpoint;
+
 /// Custom interface for Tensorboard
 pub mod tensorboard;
 
@@ -1,4 +1,5 @@
 use super::{ModuleCopy, Module, WeightCopyError};
+use serde::{Deserialize, Serialize};
 use tch::{Tensor, nn};
 
 /// The Rectified Linear Units activation function
@@ -16,6 +17,10 @@ impl Module for ReLU {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         input.relu()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
 }
 
 impl ModuleCopy for ReLU {
@@ -37,12 +42,70 @@ impl Module for GeLU {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         input.gelu()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
 }
 
 impl ModuleCopy for GeLU {
     fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
 }
 
+/// The tanh approximation of the GeLU activation function, as used by GPT-2
+#[derive(Debug)]
+pub struct GeLUTanh;
+
+impl Module for GeLUTanh {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let inner = (&input + &input * &input * &input * 0.044715) * (2. / std::f64::consts::PI).sqrt();
+        input * 0.5 * (inner.tanh() + 1.)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for GeLUTanh {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The Sigmoid Linear Units (Swish) activation function
+#[derive(Debug)]
+pub struct SiLU;
+
+impl Module for SiLU {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.silu()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for SiLU {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// Swish is another name for SiLU
+pub type Swish = SiLU;
+
 /// The sigmoid activation function
 #[derive(Debug)]
 pub struct Sigmoid;
@@ -58,12 +121,235 @@ impl Module for Sigmoid {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         input.sigmoid()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
 }
 
 impl ModuleCopy for Sigmoid {
     fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
 }
 
+/// The hyperbolic tangent activation function
+#[derive(Debug)]
+pub struct Tanh;
+
+impl Module for Tanh {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.tanh()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Tanh {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The softmax function over one dimension, so the values along it are positive and sum to 1
+#[derive(Debug, Clone, Copy)]
+pub struct Softmax {
+    pub dim: i64,
+}
+
+impl Softmax {
+    pub fn new(dim: i64) -> Self {
+        Softmax { dim }
+    }
+}
+
+impl Module for Softmax {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.softmax(self.dim, input.kind())
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Softmax {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The leaky rectified linear units activation function, negative inputs are multiplied by negative_slope (0.01 by default)
+#[derive(Debug, Clone, Copy)]
+pub struct LeakyReLU {
+    pub negative_slope: f64,
+}
+
+impl Default for LeakyReLU {
+    fn default() -> Self {
+        LeakyReLU { negative_slope: 0.01 }
+    }
+}
+
+impl LeakyReLU {
+    pub fn new(negative_slope: f64) -> Self {
+        LeakyReLU { negative_slope }
+    }
+}
+
+impl Module for LeakyReLU {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        // tch's leaky_relu only has the default slope
+        if self.negative_slope == LeakyReLU::default().negative_slope {
+            input.leaky_relu()
+        } else {
+            input.relu() - (-input).relu() * self.negative_slope
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for LeakyReLU {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The exponential linear units activation function, negative inputs become alpha * (exp(x) - 1) (alpha is 1 by default)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy)]
+pub struct ELU {
+    pub alpha: f64,
+}
+
+impl Default for ELU {
+    fn default() -> Self {
+        ELU { alpha: 1. }
+    }
+}
+
+impl ELU {
+    pub fn new(alpha: f64) -> Self {
+        ELU { alpha }
+    }
+}
+
+impl Module for ELU {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        // tch's elu only has the default alpha, other alphas scale the negative part
+        if self.alpha == ELU::default().alpha {
+            input.elu()
+        } else {
+            input.relu() + input.clamp_max(0.).elu() * self.alpha
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for ELU {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The Mish activation function, x * tanh(softplus(x))
+#[derive(Debug)]
+pub struct Mish;
+
+impl Module for Mish {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.mish()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Mish {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// The softplus activation function, log(1 + exp(beta * x)) / beta, which is linear once beta * x is above threshold (beta is 1 and threshold is 20 by default)
+#[derive(Debug, Clone, Copy)]
+pub struct Softplus {
+    pub beta: f64,
+    pub threshold: f64,
+}
+
+impl Default for Softplus {
+    fn default() -> Self {
+        Softplus { beta: 1., threshold: 20. }
+    }
+}
+
+impl Softplus {
+    pub fn new(beta: f64, threshold: f64) -> Self {
+        Softplus { beta, threshold }
+    }
+}
+
+impl Module for Softplus {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        // tch's softplus only has beta 1 and threshold 20, scaling the input handles other betas
+        if self.threshold == Softplus::default().threshold {
+            (input * self.beta).softplus() / self.beta
+        } else {
+            let scaled = &input * self.beta;
+            let soft = scaled.clamp_max(self.threshold).exp().log1p() / self.beta;
+            input.where_self(&scaled.gt(self.threshold), &soft)
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Softplus {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
 /// The Parameterized linear units activation function
 #[derive(Debug)]
 pub struct PReLU {
@@ -71,9 +357,15 @@ pub struct PReLU {
 }
 
 impl PReLU {
+    /// One negative slope shared by every input
     pub fn new(vs: nn::Path) -> Self {
+        Self::per_channel(vs, 1)
+    }
+
+    /// A negative slope for each channel, where channels are dimension 1 of the input (ex. (batch size, channels, ...))
+    pub fn per_channel(vs: nn::Path, num_channels: i64) -> Self {
         PReLU {
-            weight: vs.set_group(1).randn("weight", &[1], 0.0, 0.02)
+            weight: vs.set_group(1).randn("weight", &[num_channels], 0.0, 0.02)
         }
     }
 }
@@ -89,6 +381,10 @@ impl Module for PReLU {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         input.prelu(&self.weight)
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.weight.shallow_clone())]
+    }
 }
 
 impl ModuleCopy for PReLU {
@@ -102,4 +398,62 @@ impl ModuleCopy for PReLU {
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+/// An activation function chosen at runtime, for example from a config
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Activation {
+    ReLU,
+    #[default]
+    GeLU,
+    GeLUTanh,
+    SiLU,
+    Sigmoid,
+    Tanh,
+    /// Softmax over the given dimension
+    Softmax(i64),
+    /// LeakyReLU with the given negative slope
+    LeakyReLU(f64),
+    /// ELU with the given alpha
+    ELU(f64),
+    Mish,
+    /// Softplus with the given beta and threshold
+    Softplus {
+        beta: f64,
+        threshold: f64,
+    },
+}
+
+impl Module for Activation {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        match self {
+            Activation::ReLU => ReLU.forward(input),
+            Activation::GeLU => GeLU.forward(input),
+            Activation::GeLUTanh => GeLUTanh.forward(input),
+            Activation::SiLU => SiLU.forward(input),
+            Activation::Sigmoid => Sigmoid.forward(input),
+            Activation::Tanh => Tanh.forward(input),
+            Activation::Softmax(dim) => Softmax::new(*dim).forward(input),
+            Activation::LeakyReLU(negative_slope) => LeakyReLU::new(*negative_slope).forward(input),
+            Activation::ELU(alpha) => ELU::new(*alpha).forward(input),
+            Activation::Mish => Mish.forward(input),
+            Activation::Softplus { beta, threshold } => Softplus::new(*beta, *threshold).forward(input),
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Activation {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
@@ -0,0 +1,238 @@
+use super::{ModuleCopy, Module, WeightCopyError};
+use tch::{Tensor, nn};
+
+/// Settings for convolution layers, ND is i64 for 1d layers and [i64; 2] for 2d layers
+#[derive(Debug, Clone, Copy)]
+pub struct ConvConfig<ND> {
+    pub stride: ND,
+    pub padding: ND,
+    pub dilation: ND,
+    pub groups: i64,
+    pub bias: bool,
+}
+
+impl Default for ConvConfig<i64> {
+    fn default() -> Self {
+        ConvConfig { stride: 1, padding: 0, dilation: 1, groups: 1, bias: true }
+    }
+}
+
+impl Default for ConvConfig<[i64; 2]> {
+    fn default() -> Self {
+        ConvConfig { stride: [1, 1], padding: [0, 0], dilation: [1, 1], groups: 1, bias: true }
+    }
+}
+
+/// Settings for transposed convolution layers, output_padding adds to one side of the output to pick between the sizes a strided convolution maps to the same input size
+#[derive(Debug, Clone, Copy)]
+pub struct ConvTransposeConfig<ND> {
+    pub stride: ND,
+    pub padding: ND,
+    pub output_padding: ND,
+    pub dilation: ND,
+    pub groups: i64,
+    pub bias: bool,
+}
+
+impl Default for ConvTransposeConfig<[i64; 2]> {
+    fn default() -> Self {
+        ConvTransposeConfig { stride: [1, 1], padding: [0, 0], output_padding: [0, 0], dilation: [1, 1], groups: 1, bias: true }
+    }
+}
+
+/// Create a convolution weight and an optional bias of size out_channels, both initialized like PyTorch (uniform in +-1/sqrt(fan_in), where fan_in is shape[1] * kernel size)
+fn conv_parameters(vs: &nn::Path, shape: &[i64], out_channels: Option<i64>) -> (Tensor, Option<Tensor>) {
+    let wd = vs.set_group(1);
+    let no_wd = vs.set_group(0);
+    let bound = 1. / (shape[1..].iter().product::<i64>() as f64).sqrt();
+    (
+        wd.var("weight", shape, nn::Init::Uniform { lo: -bound, up: bound }),
+        out_channels.map(|out_channels| no_wd.var("bias", &[out_channels], nn::Init::Uniform { lo: -bound, up: bound })),
+    )
+}
+
+fn conv_named_parameters(ws: &Tensor, bs: &Option<Tensor>) -> Vec<(String, Tensor)> {
+    let mut parameters = vec![("weight".to_string(), ws.shallow_clone())];
+    if let Some(bs) = bs {
+        parameters.push(("bias".to_string(), bs.shallow_clone()));
+    }
+    parameters
+}
+
+fn conv_copy(ws: &mut Tensor, bs: &mut Option<Tensor>, source_ws: &Tensor, source_bs: &Option<Tensor>) -> Result<(), WeightCopyError> {
+    if ws.size() != source_ws.size() || bs.as_ref().map(Tensor::size) != source_bs.as_ref().map(Tensor::size) {
+        return Err(WeightCopyError::SizeMismatch);
+    }
+    tch::no_grad(|| {
+        ws.copy_(source_ws);
+        if let (Some(bs), Some(source_bs)) = (bs, source_bs) {
+            bs.copy_(source_bs);
+        }
+    });
+    Ok(())
+}
+
+/// A 1d convolution over inputs of shape (batch size, in channels, length)
+#[derive(Debug)]
+pub struct Conv1d {
+    pub ws: Tensor,
+    pub bs: Option<Tensor>,
+    config: ConvConfig<i64>,
+}
+
+impl Clone for Conv1d {
+    fn clone(&self) -> Self {
+        Conv1d {
+            ws: self.ws.copy(),
+            bs: self.bs.as_ref().map(Tensor::copy),
+            config: self.config,
+        }
+    }
+}
+
+impl Conv1d {
+    pub fn new(vs: &nn::Path, in_channels: i64, out_channels: i64, kernel_size: i64, config: ConvConfig<i64>) -> Self {
+        let (ws, bs) = conv_parameters(
+            vs,
+            &[out_channels, in_channels / config.groups, kernel_size],
+            config.bias.then_some(out_channels),
+        );
+        Conv1d { ws, bs, config }
+    }
+}
+
+impl Module for Conv1d {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.conv1d(&self.ws, self.bs.as_ref(), &[self.config.stride], &[self.config.padding], &[self.config.dilation], self.config.groups)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        conv_named_parameters(&self.ws, &self.bs)
+    }
+}
+
+impl ModuleCopy for Conv1d {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        conv_copy(&mut self.ws, &mut self.bs, &source.ws, &source.bs)
+    }
+}
+
+/// A 2d convolution over inputs of shape (batch size, in channels, height, width)
+#[derive(Debug)]
+pub struct Conv2d {
+    pub ws: Tensor,
+    pub bs: Option<Tensor>,
+    config: ConvConfig<[i64; 2]>,
+}
+
+impl Clone for Conv2d {
+    fn clone(&self) -> Self {
+        Conv2d {
+            ws: self.ws.copy(),
+            bs: self.bs.as_ref().map(Tensor::copy),
+            config: self.config,
+        }
+    }
+}
+
+impl Conv2d {
+    pub fn new(vs: &nn::Path, in_channels: i64, out_channels: i64, kernel_size: [i64; 2], config: ConvConfig<[i64; 2]>) -> Self {
+        let (ws, bs) = conv_parameters(
+            vs,
+            &[out_channels, in_channels / config.groups, kernel_size[0], kernel_size[1]],
+            config.bias.then_some(out_channels),
+        );
+        Conv2d { ws, bs, config }
+    }
+}
+
+impl Module for Conv2d {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.conv2d(&self.ws, self.bs.as_ref(), &self.config.stride, &self.config.padding, &self.config.dilation, self.config.groups)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        conv_named_parameters(&self.ws, &self.bs)
+    }
+}
+
+impl ModuleCopy for Conv2d {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        conv_copy(&mut self.ws, &mut self.bs, &source.ws, &source.bs)
+    }
+}
+
+/// A 2d transposed convolution (used for upsampling) over inputs of shape (batch size, in channels, height, width)
+#[derive(Debug)]
+pub struct ConvTranspose2d {
+    pub ws: Tensor,
+    pub bs: Option<Tensor>,
+    config: ConvTransposeConfig<[i64; 2]>,
+}
+
+impl Clone for ConvTranspose2d {
+    fn clone(&self) -> Self {
+        ConvTranspose2d {
+            ws: self.ws.copy(),
+            bs: self.bs.as_ref().map(Tensor::copy),
+            config: self.config,
+        }
+    }
+}
+
+impl ConvTranspose2d {
+    pub fn new(vs: &nn::Path, in_channels: i64, out_channels: i64, kernel_size: [i64; 2], config: ConvTransposeConfig<[i64; 2]>) -> Self {
+        // Transposed convolution weights are stored as (in, out / groups, kernel height, kernel width)
+        let (ws, bs) = conv_parameters(
+            vs,
+            &[in_channels, out_channels / config.groups, kernel_size[0], kernel_size[1]],
+            config.bias.then_some(out_channels),
+        );
+        ConvTranspose2d { ws, bs, config }
+    }
+}
+
+impl Module for ConvTranspose2d {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.conv_transpose2d(
+            &self.ws,
+            self.bs.as_ref(),
+            &self.config.stride,
+            &self.config.padding,
+            &self.config.output_padding,
+            self.config.groups,
+            &self.config.dilation,
+        )
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        conv_named_parameters(&self.ws, &self.bs)
+    }
+}
+
+impl ModuleCopy for ConvTranspose2d {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        conv_copy(&mut self.ws, &mut self.bs, &source.ws, &source.bs)
+    }
+}
@@ -5,13 +5,16 @@ use tch::{Tensor, nn};
 pub struct Linear {
     pub ws: Tensor,
     pub bs: Tensor,
+    /// False for no_bias layers, whose zero bias is a buffer rather than a parameter
+    trainable_bias: bool,
 }
 
 impl Clone for Linear {
     fn clone(&self) -> Self {
         Linear {
             ws: self.ws.copy(),
-            bs: self.bs.copy()
+            bs: self.bs.copy(),
+            trainable_bias: self.trainable_bias,
         }
     }
 }
@@ -23,6 +26,7 @@ impl Linear {
         Linear {
             ws: wd.randn("weight", &[out_dim, in_dim], 0.0, 0.02),
             bs: no_wd.zeros("bias", &[out_dim]),
+            trainable_bias: true,
         }
     }
 
@@ -32,6 +36,17 @@ impl Linear {
         Linear {
             ws: wd.randn("weight", &[out_dim, in_dim], 0.0, 0.02),
             bs: no_wd.zeros_no_train("bias", &[out_dim]),
+            trainable_bias: false,
+        }
+    }
+
+    /// A linear layer which uses an existing (out_dim, in_dim) weight, such as an embedding table, and only creates its own bias
+    pub fn tied(vs: &nn::Path, ws: &Tensor) -> Self {
+        let no_wd = vs.set_group(0);
+        Linear {
+            ws: ws.shallow_clone(),
+            bs: no_wd.zeros("bias", &[ws.size()[0]]),
+            trainable_bias: true,
         }
     }
 
@@ -42,6 +57,7 @@ impl Linear {
         Linear {
             ws: wd.randn("weight", &[out_dim, in_dim], 0.0, 1. / (in_dim as f64).sqrt()),
             bs: no_wd.randn("bias", &[out_dim], 0.0, 1. / (out_dim as f64).sqrt()),
+            trainable_bias: true,
         }
     }
 }
@@ -57,6 +73,22 @@ impl Module for Linear {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         input.matmul(&self.ws.tr()) + &self.bs
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = vec![("weight".to_string(), self.ws.shallow_clone())];
+        if self.trainable_bias {
+            parameters.push(("bias".to_string(), self.bs.shallow_clone()));
+        }
+        parameters
+    }
+
+    fn named_buffers(&self) -> Vec<(String, Tensor)> {
+        if self.trainable_bias {
+            Vec::new()
+        } else {
+            vec![("bias".to_string(), self.bs.shallow_clone())]
+        }
+    }
 }
 
 impl ModuleCopy for Linear {
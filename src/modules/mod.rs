@@ -10,6 +10,12 @@ pub use sequential::*;
 /// Transformer Layers
 mod transformer;
 pub use transformer::*;
+/// Convolution Layers
+mod conv;
+pub use conv::*;
+/// Pooling and Reshaping Layers
+mod pooling;
+pub use pooling::*;
 /// RNN Layers
 mod rnn;
 pub use rnn::*;
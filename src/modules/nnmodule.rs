@@ -1,3 +1,9 @@
+use std::{collections::HashMap, fmt, path::Path};
+use tch::{TchError, Tensor};
+
+/// A map from dotted parameter names to tensors
+pub type StateDict = HashMap<String, Tensor>;
+
 /// A trait for some basic functions a module should have
 pub trait Module: std::fmt::Debug + Send {
     type Input;
@@ -6,8 +12,101 @@ pub trait Module: std::fmt::Debug + Send {
     fn train(&mut self);
     fn eval(&mut self);
     fn forward(&mut self, input: Self::Input) -> Self::Output;
+
+    /// The parameters of this module and its submodules, named with dotted paths relative to this module (ex. "3.attn.key.weight")
+    fn named_parameters(&self) -> Vec<(String, Tensor)>;
+
+    /// Non-trainable state of this module and its submodules which is saved with the parameters (ex. BatchNorm running statistics), named like named_parameters
+    fn named_buffers(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+
+    /// The parameters of this module and its submodules
+    fn parameters(&self) -> Vec<Tensor> {
+        self.named_parameters().into_iter().map(|(_, tensor)| tensor).collect()
+    }
+
+    /// Stop gradients from flowing into this module's parameters, so optimizers leave them unchanged
+    fn freeze(&mut self) {
+        for parameter in self.parameters() {
+            let _ = parameter.set_requires_grad(false);
+        }
+    }
+
+    /// Allow this module's parameters to be trained again after freezing
+    fn unfreeze(&mut self) {
+        for parameter in self.parameters() {
+            let _ = parameter.set_requires_grad(true);
+        }
+    }
+
+    /// The module's parameters and buffers keyed by their dotted names, detached from the graph (but sharing memory with the module)
+    fn state_dict(&self) -> StateDict {
+        self.named_parameters().into_iter()
+            .chain(self.named_buffers())
+            .map(|(name, tensor)| (name, tensor.detach()))
+            .collect()
+    }
+
+    /// Load parameters and buffers from a state dict, returning the keys which didn't match.
+    /// In strict mode any missing or unexpected key is an error, mismatched shapes are always an error.
+    /// Nothing is loaded if an error is returned.
+    fn load_state_dict(&mut self, state_dict: &StateDict, strict: bool) -> Result<StateDictKeys, StateDictError> {
+        let mut parameters = self.named_parameters();
+        parameters.extend(self.named_buffers());
+        let shapes = state_dict.iter().map(|(name, tensor)| (name.clone(), tensor.size())).collect();
+        let keys = match_state_dict(&parameters, &shapes, strict)?;
+        tch::no_grad(|| {
+            for (name, mut parameter) in parameters {
+                if let Some(tensor) = state_dict.get(&name) {
+                    parameter.copy_(tensor);
+                }
+            }
+        });
+        Ok(keys)
+    }
+}
+
+/// Check the shapes of a state dict (given as name -> shape) against the parameters it will be loaded into
+pub(crate) fn match_state_dict(parameters: &[(String, Tensor)], shapes: &HashMap<String, Vec<i64>>, strict: bool) -> Result<StateDictKeys, StateDictError> {
+    let mut keys = StateDictKeys::default();
+    for (name, parameter) in parameters {
+        match shapes.get(name) {
+            Some(shape) if *shape != parameter.size() => return Err(StateDictError::ShapeMismatch {
+                name: name.clone(),
+                expected: parameter.size(),
+                found: shape.clone(),
+            }),
+            Some(_) => {},
+            None => keys.missing.push(name.clone()),
+        }
+    }
+    keys.unexpected = shapes.keys()
+        .filter(|key| !parameters.iter().any(|(name, _)| name == *key))
+        .cloned()
+        .collect();
+    keys.unexpected.sort();
+    if strict && !keys.is_empty() {
+        return Err(StateDictError::KeyMismatch(keys));
+    }
+    Ok(keys)
 }
 
+/// Save a state dict to a file (in the same format as VarStore::save)
+pub fn save_state_dict<T: AsRef<Path>>(state_dict: &StateDict, path: T) -> Result<(), TchError> {
+    let named_tensors: Vec<(&str, &Tensor)> = state_dict.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect();
+    Tensor::save_multi(&named_tensors, path)
+}
+
+/// Read a state dict saved with save_state_dict (or VarStore::save)
+pub fn read_state_dict<T: AsRef<Path>>(path: T) -> Result<StateDict, TchError> {
+    Ok(Tensor::load_multi(path)?.into_iter().collect())
+}
+
+/// Prefix the names of a submodule's parameters with the submodule's name
+pub(crate) fn prefix_parameters(prefix: &str, parameters: Vec<(String, Tensor)>) -> Vec<(String, Tensor)> {
+    parameters.into_iter().map(|(name, tensor)| (format!("{}.{}", prefix, name), tensor)).collect()
+}
 
 /// A trait to allow modules to copy weights
 pub trait ModuleCopy {
@@ -19,4 +118,43 @@ pub trait ModuleCopy {
 pub enum WeightCopyError {
     SizeMismatch,
     Other(String)
-}
\ No newline at end of file
+}
+
+/// Keys which didn't match between a module and a state dict
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StateDictKeys {
+    /// Parameters of the module which aren't in the state dict
+    pub missing: Vec<String>,
+    /// Tensors in the state dict which aren't parameters of the module
+    pub unexpected: Vec<String>,
+}
+
+impl StateDictKeys {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// An error type for loading state dicts
+#[derive(Debug)]
+pub enum StateDictError {
+    /// Missing or unexpected keys while loading strictly
+    KeyMismatch(StateDictKeys),
+    /// A tensor doesn't have the same shape as the parameter it would be loaded into
+    ShapeMismatch {
+        name: String,
+        expected: Vec<i64>,
+        found: Vec<i64>,
+    },
+}
+
+impl fmt::Display for StateDictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateDictError::KeyMismatch(keys) => write!(f, "Missing keys: {:?}, unexpected keys: {:?}", keys.missing, keys.unexpected),
+            StateDictError::ShapeMismatch { name, expected, found } => write!(f, "Shape mismatch for {}: expected {:?}, found {:?}", name, expected, found),
+        }
+    }
+}
+
+impl std::error::Error for StateDictError {}
@@ -1,5 +1,6 @@
 use std::borrow::Borrow;
-use tch::{Tensor, nn::{self, EmbeddingConfig, LayerNormConfig}};
+use tch::{Tensor, nn::{self, EmbeddingConfig}};
+pub use tch::nn::{BatchNormConfig, GroupNormConfig, LayerNormConfig};
 use super::{ModuleCopy, Module, WeightCopyError};
 
 /// A layer-normalization layer.
@@ -29,13 +30,23 @@ impl Module for LayerNorm {
             self.config.cudnn_enabled,
         )
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = Vec::new();
+        if let Some(ws) = &self.ws {
+            parameters.push(("weight".to_string(), ws.shallow_clone()));
+        }
+        if let Some(bs) = &self.bs {
+            parameters.push(("bias".to_string(), bs.shallow_clone()));
+        }
+        parameters
+    }
 }
 
 impl LayerNorm {
-    pub fn new<'a, T: Borrow<nn::Path<'a>>>(vs: T, normalized_shape: Vec<i64>) -> Self {
+    pub fn new<'a, T: Borrow<nn::Path<'a>>>(vs: T, normalized_shape: Vec<i64>, config: LayerNormConfig) -> Self {
         let vs = vs.borrow();
 
-        let config = LayerNormConfig::default();
         let (ws, bs) = if config.elementwise_affine {
             let ws = vs.var("weight", normalized_shape.as_slice(), config.ws_init);
             let bs = vs.var("bias", normalized_shape.as_slice(), config.bs_init);
@@ -53,6 +64,264 @@ impl LayerNorm {
     }
 }
 
+/// A root mean square normalization layer, which rescales without centering (as in T5 and LLaMA)
+#[derive(Debug)]
+pub struct RMSNorm {
+    pub ws: Tensor,
+    pub normalized_shape: Vec<i64>,
+    eps: f64,
+}
+
+impl Module for RMSNorm {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let dims: Vec<i64> = (-(self.normalized_shape.len() as i64)..0).collect();
+        let rms = (input.square().mean_dim(&dims, true, input.kind()) + self.eps).rsqrt();
+        input * rms * &self.ws
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.ws.shallow_clone())]
+    }
+}
+
+impl RMSNorm {
+    pub fn new<'a, T: Borrow<nn::Path<'a>>>(vs: T, normalized_shape: Vec<i64>, eps: f64) -> Self {
+        RMSNorm {
+            ws: vs.borrow().ones("weight", normalized_shape.as_slice()),
+            normalized_shape,
+            eps,
+        }
+    }
+}
+
+/// Batch normalization over (batch size, channels) or (batch size, channels, length) inputs.
+/// Batch statistics are used (and update the running statistics) in train mode, the running statistics are used in eval mode
+#[derive(Debug)]
+pub struct BatchNorm1d {
+    config: BatchNormConfig,
+    pub ws: Tensor,
+    pub bs: Tensor,
+    pub running_mean: Tensor,
+    pub running_var: Tensor,
+    train: bool,
+}
+
+/// Batch normalization over (batch size, channels, height, width) inputs.
+/// Batch statistics are used (and update the running statistics) in train mode, the running statistics are used in eval mode
+#[derive(Debug)]
+pub struct BatchNorm2d {
+    config: BatchNormConfig,
+    pub ws: Tensor,
+    pub bs: Tensor,
+    pub running_mean: Tensor,
+    pub running_var: Tensor,
+    train: bool,
+}
+
+/// Create the weight, bias, running mean and running variance for batch norm, the running statistics aren't trainable
+fn batch_norm_tensors(vs: &nn::Path, num_features: i64, config: &BatchNormConfig) -> (Tensor, Tensor, Tensor, Tensor) {
+    (
+        vs.var("weight", &[num_features], config.ws_init),
+        vs.var("bias", &[num_features], config.bs_init),
+        vs.zeros_no_train("running_mean", &[num_features]),
+        vs.ones_no_train("running_var", &[num_features]),
+    )
+}
+
+macro_rules! impl_batch_norm {
+    ($name:ident, $dims:expr) => {
+        impl $name {
+            pub fn new<'a, T: Borrow<nn::Path<'a>>>(vs: T, num_features: i64, config: BatchNormConfig) -> Self {
+                let (ws, bs, running_mean, running_var) = batch_norm_tensors(vs.borrow(), num_features, &config);
+                $name {
+                    config,
+                    ws,
+                    bs,
+                    running_mean,
+                    running_var,
+                    train: true,
+                }
+            }
+        }
+
+        impl Module for $name {
+            type Input = tch::Tensor;
+            type Output = tch::Tensor;
+
+            fn train(&mut self) {
+                self.train = true;
+            }
+
+            fn eval(&mut self) {
+                self.train = false;
+            }
+
+            fn forward(&mut self, input: Self::Input) -> Self::Output {
+                assert!($dims.contains(&input.dim()), "{} expected a {:?} dimensional input but got {} dimensions", stringify!($name), $dims, input.dim());
+                Tensor::batch_norm(
+                    &input,
+                    Some(&self.ws),
+                    Some(&self.bs),
+                    Some(&self.running_mean),
+                    Some(&self.running_var),
+                    self.train,
+                    self.config.momentum,
+                    self.config.eps,
+                    self.config.cudnn_enabled,
+                )
+            }
+
+            fn named_parameters(&self) -> Vec<(String, Tensor)> {
+                vec![
+                    ("weight".to_string(), self.ws.shallow_clone()),
+                    ("bias".to_string(), self.bs.shallow_clone()),
+                ]
+            }
+
+            fn named_buffers(&self) -> Vec<(String, Tensor)> {
+                vec![
+                    ("running_mean".to_string(), self.running_mean.shallow_clone()),
+                    ("running_var".to_string(), self.running_var.shallow_clone()),
+                ]
+            }
+        }
+
+        impl ModuleCopy for $name {
+            fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+                if self.ws.size() != source.ws.size() {
+                    return Err(WeightCopyError::SizeMismatch);
+                }
+                tch::no_grad(|| {
+                    self.ws.copy_(&source.ws);
+                    self.bs.copy_(&source.bs);
+                    self.running_mean.copy_(&source.running_mean);
+                    self.running_var.copy_(&source.running_var);
+                });
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_batch_norm!(BatchNorm1d, [2, 3]);
+impl_batch_norm!(BatchNorm2d, [4]);
+
+/// Group normalization, which normalizes groups of channels in each sample of a (batch size, channels, ...) input, so it behaves the same in train and eval mode
+#[derive(Debug)]
+pub struct GroupNorm {
+    config: GroupNormConfig,
+    pub ws: Option<Tensor>,
+    pub bs: Option<Tensor>,
+    pub num_groups: i64,
+    pub num_channels: i64,
+}
+
+impl GroupNorm {
+    pub fn new<'a, T: Borrow<nn::Path<'a>>>(vs: T, num_groups: i64, num_channels: i64, config: GroupNormConfig) -> Self {
+        assert!(num_channels % num_groups == 0, "Number of channels ({}) must be divisible by number of groups ({})!", num_channels, num_groups);
+        let vs = vs.borrow();
+        let (ws, bs) = if config.affine {
+            (Some(vs.var("weight", &[num_channels], config.ws_init)), Some(vs.var("bias", &[num_channels], config.bs_init)))
+        } else {
+            (None, None)
+        };
+        GroupNorm {
+            config,
+            ws,
+            bs,
+            num_groups,
+            num_channels,
+        }
+    }
+}
+
+impl Module for GroupNorm {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.group_norm(self.num_groups, self.ws.as_ref(), self.bs.as_ref(), self.config.eps, self.config.cudnn_enabled)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = Vec::new();
+        if let Some(ws) = &self.ws {
+            parameters.push(("weight".to_string(), ws.shallow_clone()));
+        }
+        if let Some(bs) = &self.bs {
+            parameters.push(("bias".to_string(), bs.shallow_clone()));
+        }
+        parameters
+    }
+}
+
+impl ModuleCopy for GroupNorm {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        if self.num_channels != source.num_channels || self.ws.is_some() != source.ws.is_some() {
+            return Err(WeightCopyError::SizeMismatch);
+        }
+        tch::no_grad(|| {
+            if let (Some(ws), Some(source_ws)) = (&mut self.ws, &source.ws) {
+                ws.copy_(source_ws);
+            }
+            if let (Some(bs), Some(source_bs)) = (&mut self.bs, &source.bs) {
+                bs.copy_(source_bs);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Randomly zeroes elements with probability p in train mode (scaling the rest by 1 / (1 - p)), does nothing in eval mode
+#[derive(Debug, Clone)]
+pub struct Dropout {
+    pub p: f64,
+    train: bool,
+}
+
+impl Dropout {
+    pub fn new(p: f64) -> Self {
+        assert!((0. ..=1.).contains(&p), "Dropout probability ({}) must be between 0 and 1!", p);
+        Dropout { p, train: true }
+    }
+}
+
+impl Module for Dropout {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {
+        self.train = true;
+    }
+
+    fn eval(&mut self) {
+        self.train = false;
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.dropout(self.p, self.train)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Dropout {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
 /// An embedding layer.
 ///
 /// An embedding layer acts as a simple lookup table that stores embeddings.
@@ -80,6 +349,10 @@ impl Module for Embedding {
             self.config.sparse,
         )
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.ws.shallow_clone())]
+    }
 }
 
 impl Embedding {
@@ -91,6 +364,14 @@ impl Embedding {
             config,
         }
     }
+
+    /// Another handle to the same embedding table, updates to either are seen by both
+    pub fn shared(&self) -> Self {
+        Embedding {
+            ws: self.ws.shallow_clone(),
+            config: self.config,
+        }
+    }
 }
 
 /// A layer defined by a closure
@@ -128,6 +409,10 @@ impl<'a> Module for Func<'a> {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         (*self.f)(&input, self.train)
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        Vec::new()
+    }
 }
 
 impl ModuleCopy for LayerNorm {
@@ -156,6 +441,19 @@ impl ModuleCopy for LayerNorm {
     }
 }
 
+impl ModuleCopy for RMSNorm {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        if self.ws.size() != source.ws.size() {
+            Err(WeightCopyError::SizeMismatch)
+        } else {
+            tch::no_grad(|| {
+                self.ws.copy_(&source.ws);
+            });
+            Ok(())
+        }
+    }
+}
+
 impl ModuleCopy for Embedding {
     fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
         if self.ws.size() != source.ws.size() {
@@ -0,0 +1,180 @@
+use super::{ModuleCopy, Module, WeightCopyError};
+
+/// Max pooling over the last 1, 2 or 3 dimensions (the length of kernel_size), such as (batch size, channels, height, width) for 2d pooling
+#[derive(Debug, Clone)]
+pub struct MaxPool {
+    pub kernel_size: Vec<i64>,
+    pub stride: Vec<i64>,
+    pub padding: Vec<i64>,
+}
+
+impl MaxPool {
+    /// Non-overlapping windows (the stride is the kernel size) with no padding
+    pub fn new(kernel_size: &[i64]) -> Self {
+        Self::with_stride(kernel_size, kernel_size, &vec![0; kernel_size.len()])
+    }
+
+    pub fn with_stride(kernel_size: &[i64], stride: &[i64], padding: &[i64]) -> Self {
+        assert!((1..=3).contains(&kernel_size.len()), "Only 1d, 2d and 3d pooling is supported!");
+        MaxPool {
+            kernel_size: kernel_size.to_vec(),
+            stride: stride.to_vec(),
+            padding: padding.to_vec(),
+        }
+    }
+}
+
+impl Module for MaxPool {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let dilation = vec![1; self.kernel_size.len()];
+        match self.kernel_size.len() {
+            1 => input.max_pool1d(&self.kernel_size, &self.stride, &self.padding, &dilation, false),
+            2 => input.max_pool2d(&self.kernel_size, &self.stride, &self.padding, &dilation, false),
+            _ => input.max_pool3d(&self.kernel_size, &self.stride, &self.padding, &dilation, false),
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, tch::Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for MaxPool {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// Average pooling over the last 1, 2 or 3 dimensions (the length of kernel_size), padding is included in the averages
+#[derive(Debug, Clone)]
+pub struct AvgPool {
+    pub kernel_size: Vec<i64>,
+    pub stride: Vec<i64>,
+    pub padding: Vec<i64>,
+}
+
+impl AvgPool {
+    /// Non-overlapping windows (the stride is the kernel size) with no padding
+    pub fn new(kernel_size: &[i64]) -> Self {
+        Self::with_stride(kernel_size, kernel_size, &vec![0; kernel_size.len()])
+    }
+
+    pub fn with_stride(kernel_size: &[i64], stride: &[i64], padding: &[i64]) -> Self {
+        assert!((1..=3).contains(&kernel_size.len()), "Only 1d, 2d and 3d pooling is supported!");
+        AvgPool {
+            kernel_size: kernel_size.to_vec(),
+            stride: stride.to_vec(),
+            padding: padding.to_vec(),
+        }
+    }
+}
+
+impl Module for AvgPool {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        match self.kernel_size.len() {
+            1 => input.avg_pool1d(&self.kernel_size, &self.stride, &self.padding, false, true),
+            2 => input.avg_pool2d(&self.kernel_size, &self.stride, &self.padding, false, true, None),
+            _ => input.avg_pool3d(&self.kernel_size, &self.stride, &self.padding, false, true, None),
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, tch::Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for AvgPool {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// Average pooling to a fixed output size over the last 1, 2 or 3 dimensions (the length of output_size), whatever the input size
+#[derive(Debug, Clone)]
+pub struct AdaptiveAvgPool {
+    pub output_size: Vec<i64>,
+}
+
+impl AdaptiveAvgPool {
+    pub fn new(output_size: &[i64]) -> Self {
+        assert!((1..=3).contains(&output_size.len()), "Only 1d, 2d and 3d pooling is supported!");
+        AdaptiveAvgPool {
+            output_size: output_size.to_vec(),
+        }
+    }
+}
+
+impl Module for AdaptiveAvgPool {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        match self.output_size.len() {
+            1 => input.adaptive_avg_pool1d(&self.output_size),
+            2 => input.adaptive_avg_pool2d(&self.output_size),
+            _ => input.adaptive_avg_pool3d(&self.output_size),
+        }
+    }
+
+    fn named_parameters(&self) -> Vec<(String, tch::Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for AdaptiveAvgPool {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
+
+/// Flatten the dimensions start_dim..=end_dim into one, by default everything but the batch dimension
+#[derive(Debug, Clone)]
+pub struct Flatten {
+    pub start_dim: i64,
+    pub end_dim: i64,
+}
+
+impl Default for Flatten {
+    fn default() -> Self {
+        Flatten { start_dim: 1, end_dim: -1 }
+    }
+}
+
+impl Flatten {
+    pub fn new(start_dim: i64, end_dim: i64) -> Self {
+        Flatten { start_dim, end_dim }
+    }
+}
+
+impl Module for Flatten {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+
+    fn train(&mut self) {}
+
+    fn eval(&mut self) {}
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        input.flatten(self.start_dim, self.end_dim)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, tch::Tensor)> {
+        Vec::new()
+    }
+}
+
+impl ModuleCopy for Flatten {
+    fn copy(&mut self, _: &Self) -> Result<(), WeightCopyError> {Ok(())}
+}
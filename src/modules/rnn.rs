@@ -0,0 +1,291 @@
+use super::{ModuleCopy, Module, WeightCopyError};
+use tch::{Tensor, nn};
+pub use tch::nn::RNNConfig;
+
+/// The flattened weights shared by all recurrent layers (only used internally)
+#[derive(Debug)]
+struct RNNWeights {
+    flat_weights: Vec<Tensor>,
+    names: Vec<String>,
+    hidden_dim: i64,
+    config: RNNConfig,
+}
+
+impl RNNWeights {
+    /// Create weights in the same layout (and with the same names) as PyTorch, `gate_dim` is hidden_dim times the number of gates
+    fn new(vs: &nn::Path, in_dim: i64, hidden_dim: i64, gate_dim: i64, config: RNNConfig) -> Self {
+        let wd = vs.set_group(1);
+        let no_wd = vs.set_group(0);
+        let bound = 1. / (hidden_dim as f64).sqrt();
+        let num_directions = if config.bidirectional { 2 } else { 1 };
+        let mut flat_weights = Vec::new();
+        let mut names = Vec::new();
+        for layer_idx in 0..config.num_layers {
+            for direction_idx in 0..num_directions {
+                let in_dim = if layer_idx == 0 { in_dim } else { hidden_dim * num_directions };
+                let suffix = if direction_idx == 1 { "_reverse" } else { "" };
+                let (w_ih, w_hh) = (format!("weight_ih_l{}{}", layer_idx, suffix), format!("weight_hh_l{}{}", layer_idx, suffix));
+                flat_weights.push(wd.uniform(&w_ih, &[gate_dim, in_dim], -bound, bound));
+                flat_weights.push(wd.uniform(&w_hh, &[gate_dim, hidden_dim], -bound, bound));
+                names.push(w_ih);
+                names.push(w_hh);
+                if config.has_biases {
+                    let (b_ih, b_hh) = (format!("bias_ih_l{}{}", layer_idx, suffix), format!("bias_hh_l{}{}", layer_idx, suffix));
+                    flat_weights.push(no_wd.uniform(&b_ih, &[gate_dim], -bound, bound));
+                    flat_weights.push(no_wd.uniform(&b_hh, &[gate_dim], -bound, bound));
+                    names.push(b_ih);
+                    names.push(b_hh);
+                }
+            }
+        }
+        RNNWeights {
+            flat_weights,
+            names,
+            hidden_dim,
+            config,
+        }
+    }
+
+    /// A zeroed hidden state for the input, shape: (num layers * num directions, batch size, hidden dim)
+    fn zero_state(&self, input: &Tensor) -> Tensor {
+        let num_directions = if self.config.bidirectional { 2 } else { 1 };
+        let batch_size = input.size()[if self.config.batch_first { 0 } else { 1 }];
+        Tensor::zeros(&[self.config.num_layers * num_directions, batch_size, self.hidden_dim], (input.kind(), input.device()))
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.names.iter().cloned().zip(self.flat_weights.iter().map(|w| w.shallow_clone())).collect()
+    }
+}
+
+impl Clone for RNNWeights {
+    fn clone(&self) -> Self {
+        RNNWeights {
+            flat_weights: self.flat_weights.iter().map(|w| w.copy()).collect(),
+            names: self.names.clone(),
+            hidden_dim: self.hidden_dim,
+            config: self.config,
+        }
+    }
+}
+
+impl ModuleCopy for RNNWeights {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        if self.flat_weights.len() != source.flat_weights.len()
+            || self.flat_weights.iter().zip(source.flat_weights.iter()).any(|(t, s)| t.size() != s.size()) {
+            return Err(WeightCopyError::SizeMismatch);
+        }
+        tch::no_grad(|| {
+            for (t, s) in self.flat_weights.iter_mut().zip(source.flat_weights.iter()) {
+                t.copy_(s);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A Long Short-Term Memory layer, returning the outputs and the final (hidden, cell) state
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub struct LSTM {
+    weights: RNNWeights,
+    train: bool,
+}
+
+impl LSTM {
+    pub fn new(vs: &nn::Path, in_dim: i64, hidden_dim: i64, config: RNNConfig) -> Self {
+        LSTM {
+            weights: RNNWeights::new(vs, in_dim, hidden_dim, 4 * hidden_dim, config),
+            train: true,
+        }
+    }
+
+    /// Run the LSTM starting from a given (hidden, cell) state
+    pub fn forward_with_state(&mut self, input: &Tensor, state: (&Tensor, &Tensor)) -> (Tensor, (Tensor, Tensor)) {
+        let weights = &self.weights;
+        let (output, h, c) = input.lstm(
+            &[state.0, state.1],
+            &weights.flat_weights.iter().collect::<Vec<_>>(),
+            weights.config.has_biases,
+            weights.config.num_layers,
+            weights.config.dropout,
+            self.train,
+            weights.config.bidirectional,
+            weights.config.batch_first,
+        );
+        (output, (h, c))
+    }
+}
+
+impl Module for LSTM {
+    type Input = tch::Tensor;
+    type Output = (tch::Tensor, (tch::Tensor, tch::Tensor));
+
+    fn train(&mut self) {
+        self.train = true;
+    }
+
+    fn eval(&mut self) {
+        self.train = false;
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let zeros = self.weights.zero_state(&input);
+        self.forward_with_state(&input, (&zeros, &zeros))
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.weights.named_parameters()
+    }
+}
+
+impl ModuleCopy for LSTM {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.weights.copy(&source.weights)
+    }
+}
+
+/// A Gated Recurrent Unit layer, returning the outputs and the final hidden state
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub struct GRU {
+    weights: RNNWeights,
+    train: bool,
+}
+
+impl GRU {
+    pub fn new(vs: &nn::Path, in_dim: i64, hidden_dim: i64, config: RNNConfig) -> Self {
+        GRU {
+            weights: RNNWeights::new(vs, in_dim, hidden_dim, 3 * hidden_dim, config),
+            train: true,
+        }
+    }
+
+    /// Run the GRU starting from a given hidden state
+    pub fn forward_with_state(&mut self, input: &Tensor, state: &Tensor) -> (Tensor, Tensor) {
+        let weights = &self.weights;
+        input.gru(
+            state,
+            &weights.flat_weights,
+            weights.config.has_biases,
+            weights.config.num_layers,
+            weights.config.dropout,
+            self.train,
+            weights.config.bidirectional,
+            weights.config.batch_first,
+        )
+    }
+}
+
+impl Module for GRU {
+    type Input = tch::Tensor;
+    type Output = (tch::Tensor, tch::Tensor);
+
+    fn train(&mut self) {
+        self.train = true;
+    }
+
+    fn eval(&mut self) {
+        self.train = false;
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let zeros = self.weights.zero_state(&input);
+        self.forward_with_state(&input, &zeros)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.weights.named_parameters()
+    }
+}
+
+impl ModuleCopy for GRU {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.weights.copy(&source.weights)
+    }
+}
+
+/// A vanilla (Elman) recurrent layer with a tanh or ReLU nonlinearity, returning the outputs and the final hidden state
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub struct RNN {
+    weights: RNNWeights,
+    relu: bool,
+    train: bool,
+}
+
+impl RNN {
+    /// Create an RNN using the tanh nonlinearity
+    pub fn new(vs: &nn::Path, in_dim: i64, hidden_dim: i64, config: RNNConfig) -> Self {
+        RNN {
+            weights: RNNWeights::new(vs, in_dim, hidden_dim, hidden_dim, config),
+            relu: false,
+            train: true,
+        }
+    }
+
+    /// Create an RNN using the ReLU nonlinearity
+    pub fn new_relu(vs: &nn::Path, in_dim: i64, hidden_dim: i64, config: RNNConfig) -> Self {
+        RNN {
+            weights: RNNWeights::new(vs, in_dim, hidden_dim, hidden_dim, config),
+            relu: true,
+            train: true,
+        }
+    }
+
+    /// Run the RNN starting from a given hidden state
+    pub fn forward_with_state(&mut self, input: &Tensor, state: &Tensor) -> (Tensor, Tensor) {
+        let weights = &self.weights;
+        if self.relu {
+            input.rnn_relu(
+                state,
+                &weights.flat_weights,
+                weights.config.has_biases,
+                weights.config.num_layers,
+                weights.config.dropout,
+                self.train,
+                weights.config.bidirectional,
+                weights.config.batch_first,
+            )
+        } else {
+            input.rnn_tanh(
+                state,
+                &weights.flat_weights,
+                weights.config.has_biases,
+                weights.config.num_layers,
+                weights.config.dropout,
+                self.train,
+                weights.config.bidirectional,
+                weights.config.batch_first,
+            )
+        }
+    }
+}
+
+impl Module for RNN {
+    type Input = tch::Tensor;
+    type Output = (tch::Tensor, tch::Tensor);
+
+    fn train(&mut self) {
+        self.train = true;
+    }
+
+    fn eval(&mut self) {
+        self.train = false;
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        let zeros = self.weights.zero_state(&input);
+        self.forward_with_state(&input, &zeros)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.weights.named_parameters()
+    }
+}
+
+impl ModuleCopy for RNN {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.weights.copy(&source.weights)
+    }
+}
@@ -1,4 +1,6 @@
-use super::{Module};
+use super::{Module, ModuleCopy, WeightCopyError, prefix_parameters};
+use std::ops::{Index, IndexMut};
+use tch::Tensor;
 
 #[derive(Debug)]
 pub struct Connector<M1: Module, M2: Module<Input = M1::Output>> {
@@ -32,6 +34,147 @@ impl <M1: Module, M2: Module<Input = M1::Output>>Module for Connector<M1, M2> {
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         self.module2.forward(self.module1.forward(input))
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("0", self.module1.named_parameters());
+        parameters.extend(prefix_parameters("1", self.module2.named_parameters()));
+        parameters
+    }
+
+    fn named_buffers(&self) -> Vec<(String, Tensor)> {
+        let mut buffers = prefix_parameters("0", self.module1.named_buffers());
+        buffers.extend(prefix_parameters("1", self.module2.named_buffers()));
+        buffers
+    }
+}
+
+impl <M1: Module + ModuleCopy, M2: Module<Input = M1::Output> + ModuleCopy>ModuleCopy for Connector<M1, M2> {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.module1.copy(&source.module1)?;
+        self.module2.copy(&source.module2)
+    }
+}
+
+/// A tensor to tensor module in a Sequential
+pub type BoxedModule = Box<dyn Module<Input = Tensor, Output = Tensor>>;
+
+/// A sequence of tensor to tensor modules which can be built at runtime (ex. an MLP with a configurable depth).
+/// Unlike the sequential! macro every module must take and return a single tensor.
+/// The boxed modules can't be cloned, to get a target network build a second Sequential with the same layout and copy into it
+#[derive(Debug, Default)]
+pub struct Sequential {
+    modules: Vec<BoxedModule>,
+}
+
+impl Sequential {
+    pub fn new() -> Self {
+        Sequential::default()
+    }
+
+    /// Add a module to the end of the sequence
+    pub fn push<M: Module<Input = Tensor, Output = Tensor> + 'static>(&mut self, module: M) {
+        self.modules.push(Box::new(module));
+    }
+
+    /// Add a module to the end of the sequence, for chaining
+    pub fn add<M: Module<Input = Tensor, Output = Tensor> + 'static>(mut self, module: M) -> Self {
+        self.push(module);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, BoxedModule> {
+        self.modules.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, BoxedModule> {
+        self.modules.iter_mut()
+    }
+}
+
+impl From<Vec<BoxedModule>> for Sequential {
+    fn from(modules: Vec<BoxedModule>) -> Self {
+        Sequential { modules }
+    }
+}
+
+impl Index<usize> for Sequential {
+    type Output = BoxedModule;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.modules[index]
+    }
+}
+
+impl IndexMut<usize> for Sequential {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.modules[index]
+    }
+}
+
+impl Module for Sequential {
+    type Input = Tensor;
+    type Output = Tensor;
+
+    fn train(&mut self) {
+        for module in &mut self.modules {
+            module.train();
+        }
+    }
+
+    fn eval(&mut self) {
+        for module in &mut self.modules {
+            module.eval();
+        }
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        self.modules.iter_mut().fold(input, |x, module| module.forward(x))
+    }
+
+    /// Parameters are prefixed by the index of their module, like Connector
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        self.modules.iter()
+            .enumerate()
+            .flat_map(|(i, module)| prefix_parameters(&i.to_string(), module.named_parameters()))
+            .collect()
+    }
+
+    fn named_buffers(&self) -> Vec<(String, Tensor)> {
+        self.modules.iter()
+            .enumerate()
+            .flat_map(|(i, module)| prefix_parameters(&i.to_string(), module.named_buffers()))
+            .collect()
+    }
+}
+
+impl ModuleCopy for Sequential {
+    /// Copy parameters and buffers by name, so the source must have the same layout (the same parameter names and shapes)
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        let mut targets = self.named_parameters();
+        targets.extend(self.named_buffers());
+        let mut sources = source.named_parameters();
+        sources.extend(source.named_buffers());
+        if targets.len() != sources.len() || targets.iter().zip(&sources).any(|((target_name, _), (source_name, _))| target_name != source_name) {
+            return Err(WeightCopyError::Other("Sequentials have different layouts!".to_string()));
+        }
+        if targets.iter().zip(&sources).any(|((_, target), (_, source))| target.size() != source.size()) {
+            return Err(WeightCopyError::SizeMismatch);
+        }
+        tch::no_grad(|| {
+            for ((_, mut target), (_, source)) in targets.into_iter().zip(&sources) {
+                target.copy_(source);
+            }
+        });
+        Ok(())
+    }
 }
 
 // A macro for making sequentials
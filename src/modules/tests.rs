@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod linear_tests {
     use tch::{Device, Kind, Tensor, nn};
-    use crate::{modules::Module, utils::count_parameters};
+    use crate::{modules::{Module, StateDictError, StateDictKeys}, utils::count_parameters};
 
     use super::super::Linear;
 
@@ -13,19 +13,122 @@ mod linear_tests {
         let output = layer.forward(input);
         assert_eq!(output.size(), &[64, 20]);
         assert_eq!(count_parameters(&vs), 2020);
+        let names: Vec<String> = layer.named_parameters().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["weight", "bias"]);
+
+        // The zero bias of a no_bias layer isn't trained, so it's a buffer
+        let layer = Linear::no_bias(&vs.root() / "no_bias", 100, 20);
+        let names: Vec<String> = layer.named_parameters().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["weight"]);
+        assert!(layer.state_dict().contains_key("bias"));
+    }
+
+    #[test]
+    fn test_linear_state_dict() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let source = Linear::new(&(&vs.root() / "source"), 10, 5);
+        let mut target = Linear::new(&(&vs.root() / "target"), 10, 5);
+        let mut state_dict = source.state_dict();
+        assert_eq!(target.load_state_dict(&state_dict, true).unwrap(), StateDictKeys::default());
+        assert!(target.ws.equal(&source.ws));
+
+        // Strict loading fails on missing or unexpected keys, non-strict loading reports them
+        state_dict.remove("bias");
+        state_dict.insert("extra".to_string(), Tensor::zeros(&[1], (Kind::Float, Device::Cpu)));
+        assert!(matches!(target.load_state_dict(&state_dict, true), Err(StateDictError::KeyMismatch(_))));
+        let keys = target.load_state_dict(&state_dict, false).unwrap();
+        assert_eq!(keys.missing, vec!["bias"]);
+        assert_eq!(keys.unexpected, vec!["extra"]);
+
+        // Shapes must always match
+        let mut wrong = Linear::new(&(&vs.root() / "wrong"), 10, 6);
+        match wrong.load_state_dict(&source.state_dict(), false) {
+            Err(StateDictError::ShapeMismatch { name, expected, found }) => {
+                assert_eq!(name, "weight");
+                assert_eq!(expected, vec![6, 10]);
+                assert_eq!(found, vec![5, 10]);
+            },
+            _ => panic!("Expected a shape mismatch"),
+        }
     }
 }
 
 #[cfg(test)]
 mod rnn_test {
+    use tch::{Device, Kind, Tensor, nn};
+    use crate::{modules::{Module, ModuleCopy}, sequential, utils::count_parameters};
+    use super::super::{GRU, LSTM, Linear, RNN, RNNConfig};
 
+    #[test]
+    fn test_lstm() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = LSTM::new(&(&vs.root() / "lstm"), 10, 20, RNNConfig::default());
+        let input = Tensor::rand(&[64, 15, 10], (Kind::Float, Device::cuda_if_available()));
+        let (output, (h, c)) = layer.forward(input);
+        assert_eq!(output.size(), &[64, 15, 20]);
+        assert_eq!(h.size(), &[1, 64, 20]);
+        assert_eq!(c.size(), &[1, 64, 20]);
+        assert_eq!(count_parameters(&vs), 2560);
+    }
+
+    #[test]
+    fn test_gru_bidirectional() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = GRU::new(&(&vs.root() / "gru"), 10, 20, RNNConfig {
+            num_layers: 2,
+            bidirectional: true,
+            ..Default::default()
+        });
+        let input = Tensor::rand(&[64, 15, 10], (Kind::Float, Device::cuda_if_available()));
+        let (output, h) = layer.forward(input);
+        assert_eq!(output.size(), &[64, 15, 40]);
+        assert_eq!(h.size(), &[4, 64, 20]);
+        assert_eq!(count_parameters(&vs), 11_280);
+    }
+
+    #[test]
+    fn test_rnn_seq_first() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = RNN::new(&(&vs.root() / "rnn"), 10, 20, RNNConfig {
+            batch_first: false,
+            ..Default::default()
+        });
+        let input = Tensor::rand(&[15, 64, 10], (Kind::Float, Device::cuda_if_available()));
+        let (output, h) = layer.forward(input);
+        assert_eq!(output.size(), &[15, 64, 20]);
+        assert_eq!(h.size(), &[1, 64, 20]);
+        assert_eq!(count_parameters(&vs), 640);
+    }
+
+    #[test]
+    fn test_rnn_sequential_and_copy() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut seq = sequential!(
+            Linear::new(&(&vs.root() / "linear"), 100, 10),
+            LSTM::new(&(&vs.root() / "lstm"), 10, 20, RNNConfig::default())
+        );
+        let input = Tensor::rand(&[64, 15, 100], (Kind::Float, Device::cuda_if_available()));
+        let (output, _) = seq.forward(input);
+        assert_eq!(output.size(), &[64, 15, 20]);
+
+        let target_vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut source = GRU::new(&(&vs.root() / "gru"), 10, 20, RNNConfig::default());
+        let mut target = GRU::new(&(&target_vs.root() / "gru"), 10, 20, RNNConfig::default());
+        target.copy(&source).unwrap();
+        source.eval();
+        target.eval();
+        let input = Tensor::rand(&[8, 5, 10], (Kind::Float, Device::cuda_if_available()));
+        let (source_output, _) = source.forward(input.shallow_clone());
+        let (target_output, _) = target.forward(input);
+        assert!(source_output.allclose(&target_output, 1e-5, 1e-8, false));
+    }
 }
 
 #[cfg(test)]
 mod sequential_tests {
     use tch::{Device, Kind, Tensor, nn};
-    use crate::{modules::Module, sequential, utils::count_parameters};
-    use super::super::{Linear, PReLU};
+    use crate::{modules::{Module, ModuleCopy}, sequential, utils::count_parameters};
+    use super::super::{Activation, BatchNorm1d, Dropout, Linear, PReLU, Sequential};
 
     #[test]
     fn test_sequential() {
@@ -39,4 +142,277 @@ mod sequential_tests {
         assert_eq!(output.size(), &[64, 150]);
         assert_eq!(count_parameters(&vs), 5171);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_dynamic_sequential() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        // An MLP with a runtime depth
+        let (n_layers, hidden) = (4, 32);
+        let mut mlp = Sequential::new();
+        for i in 0..n_layers {
+            mlp.push(Linear::new(&(&vs.root() / i), if i == 0 { 10 } else { hidden }, hidden));
+            mlp.push(Activation::ReLU);
+        }
+        let mut mlp = mlp.add(BatchNorm1d::new(&vs.root() / "norm", hidden, Default::default()))
+            .add(Dropout::new(0.5));
+        assert_eq!(mlp.len(), 2 * n_layers + 2);
+        assert_eq!(count_parameters(&vs), 10 * 32 + 32 + 3 * (32 * 32 + 32) + 2 * 32);
+        assert_eq!(mlp.parameters().len(), 2 * 4 + 2);
+        let names: Vec<String> = mlp.named_parameters().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names[..2], ["0.weight", "0.bias"]);
+        assert_eq!(mlp.named_buffers()[0].0, "8.running_mean");
+
+        let input = Tensor::rand(&[16, 10], (Kind::Float, Device::cuda_if_available()));
+        assert_eq!(mlp.forward(input.shallow_clone()).size(), &[16, 32]);
+        // eval reaches every module (dropout and batch norm)
+        mlp.eval();
+        let running_mean = mlp.named_buffers()[0].1.copy();
+        assert!(mlp.forward(input.shallow_clone()).equal(&mlp.forward(input.shallow_clone())));
+        assert!(mlp.named_buffers()[0].1.equal(&running_mean));
+
+        // Modules can be indexed and iterated over
+        assert_eq!(mlp[0].forward(input.shallow_clone()).size(), &[16, 32]);
+        assert_eq!(mlp.iter().map(|module| module.parameters().len()).sum::<usize>(), 10);
+
+        // A target network with the same layout can copy the weights and running statistics
+        let mut target = Sequential::new();
+        for i in 0..n_layers {
+            target.push(Linear::new(&(&vs.root() / "target" / i), if i == 0 { 10 } else { hidden }, hidden));
+            target.push(Activation::ReLU);
+        }
+        let mut target = target.add(BatchNorm1d::new(&vs.root() / "target_norm", hidden, Default::default()))
+            .add(Dropout::new(0.5));
+        target.copy(&mlp).unwrap();
+        target.eval();
+        assert!(target.forward(input.shallow_clone()).equal(&mlp.forward(input)));
+        assert!(Sequential::new().add(Linear::new(&(&vs.root() / "small"), 10, 8)).copy(&mlp).is_err());
+    }
+
+    #[test]
+    fn test_dropout() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut seq = sequential!(Linear::new(&(&vs.root() / "linear"), 100, 20), Dropout::new(0.5));
+        let input = Tensor::rand(&[64, 100], (Kind::Float, Device::cuda_if_available()));
+        // Dropout is random in train mode and does nothing in eval mode
+        assert!(!seq.forward(input.shallow_clone()).equal(&seq.forward(input.shallow_clone())));
+        seq.eval();
+        assert!(seq.forward(input.shallow_clone()).equal(&seq.forward(input.shallow_clone())));
+        let mut dropout = Dropout::new(0.5);
+        dropout.eval();
+        assert!(dropout.forward(input.shallow_clone()).equal(&input));
+    }
+}
+
+#[cfg(test)]
+mod norm_tests {
+    use tch::{Device, Kind, Tensor, nn};
+    use crate::{modules::{Module, ModuleCopy}, utils::count_parameters};
+    use super::super::{BatchNorm1d, BatchNorm2d, GroupNorm, LayerNorm, LayerNormConfig, Linear, RMSNorm};
+
+    #[test]
+    fn test_rms_norm() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = RMSNorm::new(&vs.root() / "rms_norm", vec![32], 1e-6);
+        let input = Tensor::randn(&[4, 10, 32], (Kind::Float, Device::cuda_if_available())) * 5. + 2.;
+        let output = layer.forward(input);
+        assert_eq!(output.size(), &[4, 10, 32]);
+        assert_eq!(count_parameters(&vs), 32);
+        // Every position has a root mean square of 1 (but isn't centered)
+        let rms = output.square().mean_dim(&[-1], false, Kind::Float).sqrt();
+        assert!(rms.allclose(&rms.ones_like(), 1e-4, 1e-4, false));
+
+        let mut target = RMSNorm::new(&vs.root() / "target", vec![32], 1e-6);
+        let _ = layer.ws.shallow_clone().fill_(2.);
+        target.copy(&layer).unwrap();
+        assert!(target.ws.equal(&layer.ws));
+    }
+
+    #[test]
+    fn test_layer_norm_config() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = LayerNorm::new(&vs.root() / "layer_norm", vec![32], LayerNormConfig {
+            elementwise_affine: false,
+            eps: 1e-6,
+            ..Default::default()
+        });
+        let output = layer.forward(Tensor::randn(&[4, 32], (Kind::Float, Device::cuda_if_available())));
+        assert_eq!(output.size(), &[4, 32]);
+        assert_eq!(count_parameters(&vs), 0);
+    }
+
+    #[test]
+    fn test_batch_norm() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = BatchNorm2d::new(&vs.root() / "batch_norm", 8, Default::default());
+        // The running statistics are buffers, not parameters
+        assert_eq!(count_parameters(&vs), 16);
+        let names: Vec<String> = layer.named_buffers().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["running_mean", "running_var"]);
+
+        // Train mode normalizes with the batch statistics and updates the running statistics
+        let input = Tensor::randn(&[16, 8, 5, 5], (Kind::Float, Device::cuda_if_available())) * 3. + 2.;
+        let output = layer.forward(input.shallow_clone());
+        assert_eq!(output.size(), &[16, 8, 5, 5]);
+        assert!(output.mean(Kind::Float).abs().double_value(&[]) < 1e-4);
+        let running_mean = layer.running_mean.copy();
+        assert!(running_mean.allclose(&(input.mean_dim(&[0, 2, 3], false, Kind::Float) * 0.1), 1e-4, 1e-4, false));
+
+        // Eval mode uses the running statistics and leaves them unchanged
+        layer.eval();
+        let eval_output = layer.forward(input.shallow_clone());
+        assert!(layer.running_mean.equal(&running_mean));
+        let normalized = (&input - running_mean.view([1, 8, 1, 1])) / (layer.running_var.view([1, 8, 1, 1]) + 1e-5).sqrt();
+        let expected = normalized * layer.ws.view([1, 8, 1, 1]) + layer.bs.view([1, 8, 1, 1]);
+        assert!(eval_output.allclose(&expected, 1e-4, 1e-4, false));
+
+        // Copies and state dicts include the running statistics
+        let mut target = BatchNorm2d::new(&vs.root() / "target", 8, Default::default());
+        target.copy(&layer).unwrap();
+        assert!(target.running_mean.equal(&layer.running_mean) && target.running_var.equal(&layer.running_var));
+        let state_dict = layer.state_dict();
+        assert!(state_dict.contains_key("running_var"));
+        let mut loaded = BatchNorm2d::new(&vs.root() / "loaded", 8, Default::default());
+        loaded.load_state_dict(&state_dict, true).unwrap();
+        assert!(loaded.running_var.equal(&layer.running_var));
+
+        // Buffers of nested modules are named like parameters
+        let seq = crate::sequential!(Linear::new(&(&vs.root() / "linear"), 4, 6), BatchNorm1d::new(&vs.root() / "batch_norm_1d", 6, Default::default()));
+        let names: Vec<String> = seq.named_buffers().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["1.running_mean", "1.running_var"]);
+    }
+
+    #[test]
+    fn test_group_norm() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = GroupNorm::new(&vs.root() / "group_norm", 4, 8, Default::default());
+        let input = Tensor::randn(&[2, 8, 6, 6], (Kind::Float, Device::cuda_if_available())) * 3. + 2.;
+        let output = layer.forward(input.shallow_clone());
+        assert_eq!(output.size(), &[2, 8, 6, 6]);
+        assert_eq!(count_parameters(&vs), 16);
+        // Each group of 2 channels in each sample is normalized
+        let group_means = output.view([2, 4, -1]).mean_dim(&[-1], false, Kind::Float);
+        assert!(group_means.abs().max().double_value(&[]) < 1e-4);
+        layer.eval();
+        assert!(layer.forward(input).allclose(&output, 1e-6, 1e-6, false));
+    }
+}
+
+#[cfg(test)]
+mod conv_tests {
+    use tch::{Device, Kind, Tensor, nn};
+    use crate::{modules::{Module, ModuleCopy}, utils::count_parameters};
+    use super::super::{AdaptiveAvgPool, AvgPool, Conv1d, Conv2d, ConvConfig, ConvTranspose2d, ConvTransposeConfig, Flatten, MaxPool};
+
+    #[test]
+    fn test_conv1d() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = Conv1d::new(&(&vs.root() / "conv"), 4, 8, 3, ConvConfig { padding: 1, ..Default::default() });
+        let output = layer.forward(Tensor::randn(&[2, 4, 20], (Kind::Float, Device::cuda_if_available())));
+        assert_eq!(output.size(), &[2, 8, 20]);
+        assert_eq!(count_parameters(&vs), 4 * 8 * 3 + 8);
+    }
+
+    #[test]
+    fn test_conv2d() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = Conv2d::new(&(&vs.root() / "conv"), 3, 16, [3, 3], ConvConfig { stride: [2, 2], padding: [1, 1], ..Default::default() });
+        let input = Tensor::randn(&[2, 3, 32, 32], (Kind::Float, Device::cuda_if_available()));
+        assert_eq!(layer.forward(input.shallow_clone()).size(), &[2, 16, 16, 16]);
+        assert_eq!(count_parameters(&vs), 3 * 16 * 9 + 16);
+
+        // Grouped convolutions without a bias
+        let mut grouped = Conv2d::new(&(&vs.root() / "grouped"), 16, 32, [1, 1], ConvConfig { groups: 4, bias: false, ..Default::default() });
+        assert_eq!(grouped.named_parameters().len(), 1);
+        assert_eq!(grouped.forward(layer.forward(input.shallow_clone())).size(), &[2, 32, 16, 16]);
+
+        // Clones and copies are independent of the source
+        let mut target = Conv2d::new(&(&vs.root() / "target"), 3, 16, [3, 3], Default::default());
+        target.copy(&layer).unwrap();
+        let clone = layer.clone();
+        assert!(target.ws.equal(&layer.ws) && clone.ws.equal(&layer.ws));
+        let _ = layer.ws.shallow_clone().detach().fill_(0.);
+        assert!(!clone.ws.equal(&layer.ws));
+        assert!(grouped.copy(&layer).is_err());
+    }
+
+    #[test]
+    fn test_conv_transpose2d() {
+        let vs = nn::VarStore::new(Device::cuda_if_available());
+        let mut layer = ConvTranspose2d::new(&(&vs.root() / "deconv"), 16, 3, [3, 3], ConvTransposeConfig {
+            stride: [2, 2],
+            padding: [1, 1],
+            output_padding: [1, 1],
+            ..Default::default()
+        });
+        let output = layer.forward(Tensor::randn(&[2, 16, 16, 16], (Kind::Float, Device::cuda_if_available())));
+        assert_eq!(output.size(), &[2, 3, 32, 32]);
+        assert_eq!(count_parameters(&vs), 16 * 3 * 9 + 3);
+    }
+
+    #[test]
+    fn test_pooling() {
+        let input = Tensor::randn(&[2, 8, 12, 12], (Kind::Float, Device::cuda_if_available()));
+        assert_eq!(MaxPool::new(&[2, 2]).forward(input.shallow_clone()).size(), &[2, 8, 6, 6]);
+        assert_eq!(MaxPool::with_stride(&[3, 3], &[1, 1], &[1, 1]).forward(input.shallow_clone()).size(), &[2, 8, 12, 12]);
+        assert_eq!(AvgPool::new(&[3, 3]).forward(input.shallow_clone()).size(), &[2, 8, 4, 4]);
+        assert_eq!(AvgPool::new(&[2]).forward(input.flatten(2, -1)).size(), &[2, 8, 72]);
+        let pooled = AdaptiveAvgPool::new(&[1, 1]).forward(input.shallow_clone());
+        assert_eq!(pooled.size(), &[2, 8, 1, 1]);
+        assert!(pooled.flatten(1, -1).allclose(&input.mean_dim(&[2, 3], false, Kind::Float), 1e-5, 1e-5, false));
+        assert_eq!(Flatten::default().forward(pooled).size(), &[2, 8]);
+        assert_eq!(Flatten::new(0, 1).forward(input).size(), &[16, 12, 12]);
+    }
+}
+
+#[cfg(test)]
+mod activation_tests {
+    use tch::{Device, Kind, Tensor, nn};
+    use crate::{modules::{Module, ModuleCopy}, utils::count_parameters};
+    use super::super::{Activation, ELU, LeakyReLU, Mish, PReLU, SiLU, Softmax, Softplus, Swish, Tanh};
+
+    #[test]
+    fn test_activations() {
+        let input = Tensor::of_slice(&[-30., -1., 0., 1., 30.]).to_kind(Kind::Float);
+        let close = |a: &Tensor, b: &[f64]| a.allclose(&Tensor::of_slice(b).to_kind(Kind::Float), 1e-4, 1e-4, false);
+        assert!(close(&Tanh.forward(input.shallow_clone()), &[-1., -0.76159, 0., 0.76159, 1.]));
+        assert!(close(&LeakyReLU::new(0.1).forward(input.shallow_clone()), &[-3., -0.1, 0., 1., 30.]));
+        assert!(close(&ELU::default().forward(input.shallow_clone()), &[-1., -0.63212, 0., 1., 30.]));
+        assert!(close(&Softplus::default().forward(input.shallow_clone()), &[0., 0.31326, 0.69315, 1.31326, 30.]));
+        assert!(close(&Softplus::new(2., 1.).forward(input.shallow_clone()), &[0., 0.06346, 0.34657, 1., 30.]));
+        assert!(close(&ELU::new(2.).forward(input.shallow_clone()), &[-2., -1.26424, 0., 1., 30.]));
+        assert!(close(&Mish.forward(input.shallow_clone()), &[0., -0.30340, 0., 0.86510, 30.]));
+        let mut swish: Swish = SiLU;
+        assert!(close(&swish.forward(input.shallow_clone()), &[0., -0.26894, 0., 0.73106, 30.]));
+        let probs = Softmax::new(-1).forward(Tensor::randn(&[4, 6], (Kind::Float, Device::Cpu)));
+        assert!(probs.sum_dim_intlist(&[-1], false, Kind::Float).allclose(&Tensor::ones(&[4], (Kind::Float, Device::Cpu)), 1e-5, 1e-5, false));
+
+        // Large inputs don't overflow into NaN gradients
+        let input = input.set_requires_grad(true);
+        let output = ELU::default().forward(input.shallow_clone()) + Softplus::default().forward(input.shallow_clone());
+        output.sum(Kind::Float).backward();
+        assert_eq!(input.grad().isnan().any().int64_value(&[]), 0);
+    }
+
+    #[test]
+    fn test_activation_enum() {
+        let input = Tensor::randn(&[3, 8], (Kind::Float, Device::Cpu));
+        let mut activation: Activation = serde_json::from_str(r#"{"LeakyReLU":0.2}"#).unwrap();
+        assert_eq!(activation, Activation::LeakyReLU(0.2));
+        assert!(activation.forward(input.shallow_clone()).equal(&LeakyReLU::new(0.2).forward(input.shallow_clone())));
+        assert_eq!(serde_json::to_string(&Activation::Mish).unwrap(), r#""Mish""#);
+        for mut activation in [Activation::Tanh, Activation::Softmax(-1), Activation::ELU(1.), Activation::Mish, Activation::Softplus { beta: 2., threshold: 10. }] {
+            assert_eq!(activation.forward(input.shallow_clone()).size(), &[3, 8]);
+        }
+    }
+
+    #[test]
+    fn test_prelu_per_channel() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mut layer = PReLU::per_channel(&vs.root() / "prelu", 4);
+        assert_eq!(count_parameters(&vs), 4);
+        let output = layer.forward(Tensor::randn(&[2, 4, 5, 5], (Kind::Float, Device::Cpu)));
+        assert_eq!(output.size(), &[2, 4, 5, 5]);
+        let mut shared = PReLU::new(&vs.root() / "shared");
+        assert!(shared.copy(&layer).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::modules::{Dropout, Linear, ModuleCopy, Module, WeightCopyError, PositionalEncoding, prefix_parameters};
+use tch::{nn, Device, Kind, Tensor};
+use super::{alibi_bias, apply_rotary, mask_padding, KVCache};
+
+/// Repeat each of the (batch, kv head, seq len, head size) keys or values for n_rep query heads, giving (batch, kv head * n_rep, seq len, head size)
+fn repeat_kv(xs: Tensor, n_rep: i64) -> Tensor {
+    if n_rep == 1 {
+        return xs;
+    }
+    let (sz_b, n_kv_heads, sz_t, head_size) = xs.size4().unwrap();
+    xs.unsqueeze(2)
+        .expand(&[sz_b, n_kv_heads, n_rep, sz_t, head_size], false)
+        .reshape(&[sz_b, n_kv_heads * n_rep, sz_t, head_size])
+}
+
+/// Scaled dot-product multi-head attention, usable for both self attention and cross attention.
+/// Keys and values can have fewer heads than queries (grouped-query attention), each shared by n_head / n_kv_heads query heads.
+/// Rotary embeddings and ALiBi biases are only applied in self attention, where queries and keys are positions of the same sequence.
+/// The causal mask treats the queries as the last positions of the keys
+#[derive(Debug)]
+pub struct MultiHeadAttention {
+    n_head: i64,
+    n_kv_heads: i64,
+    n_embd: i64,
+    key: Linear,
+    query: Linear,
+    value: Linear,
+    proj: Linear,
+    attn_dropout: Dropout,
+    output_dropout: Dropout,
+    causal_mask: bool,
+    positional_encoding: PositionalEncoding,
+}
+
+pub struct MultiHeadAttentionProps<'a> {
+    pub p: &'a nn::Path<'a>,
+    /// Size of the queries and the output
+    pub n_embd: i64,
+    pub n_head: i64,
+    /// Number of key/value heads, must divide n_head. n_head if None
+    pub n_kv_heads: Option<i64>,
+    /// Size of the key inputs, n_embd if None
+    pub kdim: Option<i64>,
+    /// Size of the value inputs, n_embd if None
+    pub vdim: Option<i64>,
+    /// Dropout on the attention weights
+    pub dropout: f64,
+    /// Dropout on the output projection
+    pub output_dropout: f64,
+    pub causal_mask: bool,
+    /// Only Rotary and ALiBi change attention, other encodings are added to the inputs beforehand
+    pub positional_encoding: PositionalEncoding,
+}
+
+impl MultiHeadAttention {
+    pub fn new(props: MultiHeadAttentionProps) -> Self {
+        Self::with_linear(props, Linear::new)
+    }
+
+    /// Build the attention layer using `linear` to create the projections
+    pub(super) fn with_linear(props: MultiHeadAttentionProps, linear: fn(&nn::Path, i64, i64) -> Linear) -> Self {
+        let (p, n_embd, n_head) = (props.p, props.n_embd, props.n_head);
+        let n_kv_heads = props.n_kv_heads.unwrap_or(n_head);
+        assert!(n_embd % n_head == 0, "Embedding size ({}) must be divisible by number of heads ({})!", n_embd, n_head);
+        assert!(n_kv_heads > 0 && n_head % n_kv_heads == 0, "Number of heads ({}) must be divisible by number of key/value heads ({})!", n_head, n_kv_heads);
+        assert!(props.positional_encoding != PositionalEncoding::Rotary || (n_embd / n_head) % 2 == 0, "Head size ({}) must be even to use rotary embeddings!", n_embd / n_head);
+        let kv_dim = n_kv_heads * (n_embd / n_head);
+        MultiHeadAttention {
+            n_embd,
+            n_head,
+            n_kv_heads,
+            key: linear(&(p / "key"), props.kdim.unwrap_or(n_embd), kv_dim),
+            query: linear(&(p / "query"), n_embd, n_embd),
+            value: linear(&(p / "value"), props.vdim.unwrap_or(n_embd), kv_dim),
+            proj: linear(&(p / "proj"), n_embd, n_embd),
+            attn_dropout: Dropout::new(props.dropout),
+            output_dropout: Dropout::new(props.output_dropout),
+            causal_mask: props.causal_mask,
+            positional_encoding: props.positional_encoding,
+        }
+    }
+
+    /// Causal mask for `query_len` queries which are the last positions of `key_len` keys
+    fn generate_mask(query_len: i64, key_len: i64, device: Device) -> Tensor {
+        Tensor::ones(&[query_len, key_len], (Kind::Float, device)).tril(key_len - query_len).view([1, 1, query_len, key_len])
+    }
+
+    /// Project the inputs into (batch, head, seq len, head size) queries, keys and values.
+    /// Queries and keys are rotated starting from position rotary_offset, or not at all if it is None
+    fn project(&mut self, query: &Tensor, key: &Tensor, value: &Tensor, rotary_offset: Option<i64>) -> (Tensor, Tensor, Tensor) {
+        let head_size = self.n_embd / self.n_head;
+        let split_heads = |xs: Tensor, n_head: i64| {
+            let (sz_b, sz_t, _) = xs.size3().unwrap();
+            xs.view([sz_b, sz_t, n_head, head_size]).transpose(1, 2)
+        };
+        let q = split_heads(self.query.forward(query.shallow_clone()), self.n_head);
+        let k = split_heads(self.key.forward(key.shallow_clone()), self.n_kv_heads);
+        let v = split_heads(self.value.forward(value.shallow_clone()), self.n_kv_heads);
+        match rotary_offset {
+            Some(offset) if self.positional_encoding == PositionalEncoding::Rotary => (apply_rotary(&q, offset), apply_rotary(&k, offset), v),
+            _ => (q, k, v),
+        }
+    }
+
+    /// Scaled dot-product attention over (batch, head, seq len, head size) tensors (keys and values have n_kv_heads heads).
+    /// ALiBi biases are only added if self_attention is true.
+    /// Returns the attended values and the attention weights, shape: (batch, head, query len, key len)
+    fn attend(&mut self, q: &Tensor, k: &Tensor, v: &Tensor, padding_mask: Option<&Tensor>, attn_mask: Option<&Tensor>, self_attention: bool) -> (Tensor, Tensor) {
+        let (sz_q, sz_k) = (q.size()[2], k.size()[2]);
+        let k = repeat_kv(k.shallow_clone(), self.n_head / self.n_kv_heads);
+        let v = repeat_kv(v.shallow_clone(), self.n_head / self.n_kv_heads);
+        let mut att = q.matmul(&k.transpose(-2, -1)) * (1.0 / f64::sqrt(q.size()[3] as f64));
+        if self_attention && self.positional_encoding == PositionalEncoding::ALiBi {
+            att = att + alibi_bias(self.n_head, sz_q, sz_k, q.device());
+        }
+        if self.causal_mask {
+            let mask = MultiHeadAttention::generate_mask(sz_q, sz_k, q.device());
+            att = att.masked_fill(&mask.eq(0.), std::f64::NEG_INFINITY);
+        }
+        if let Some(mask) = attn_mask {
+            let mask = match mask.dim() {
+                2 => mask.view([1, 1, sz_q, sz_k]),
+                _ => mask.unsqueeze(1),
+            };
+            att = att.masked_fill(&mask.to_kind(Kind::Bool), -1e9);
+        }
+        att = mask_padding(att, padding_mask);
+        let weights = att.softmax(-1, Kind::Float);
+        let ys = self.attn_dropout.forward(weights.shallow_clone()).matmul(&v);
+        (ys, weights)
+    }
+
+    /// Merge the (batch, head, seq len, head size) attended values and project them, shape: (batch, seq len, n_embd)
+    fn merge_heads(&mut self, ys: Tensor) -> Tensor {
+        let (sz_b, _, sz_t, _) = ys.size4().unwrap();
+        let ys = ys.transpose(1, 2)
+            .contiguous()
+            .view([sz_b, sz_t, self.n_embd]);
+        self.output_dropout.forward(self.proj.forward(ys))
+    }
+
+    /// Attend from query over key and value inputs from another sequence, shapes: (batch size, query len, n_embd), (batch size, key len, kdim) and (batch size, key len, vdim).
+    /// Keys where the (batch size, key len) padding_mask or the (query len, key len) / (batch size, query len, key len) attn_mask is true are ignored.
+    /// Rotary embeddings and ALiBi biases aren't applied, use forward_self_attention when the keys are the queries' own sequence.
+    /// Returns the output, shape: (batch size, query len, n_embd), and the attention weights, shape: (batch size, n_head, query len, key len), if need_weights
+    pub fn forward_attention(&mut self, query: &Tensor, key: &Tensor, value: &Tensor, padding_mask: Option<&Tensor>, attn_mask: Option<&Tensor>, need_weights: bool) -> (Tensor, Option<Tensor>) {
+        let (q, k, v) = self.project(query, key, value, None);
+        let (ys, weights) = self.attend(&q, &k, &v, padding_mask, attn_mask, false);
+        (self.merge_heads(ys), if need_weights { Some(weights) } else { None })
+    }
+
+    /// Self attention over input, shape: (batch size, seq len, n_embd), with masks as in forward_attention.
+    /// Returns the output, shape: (batch size, seq len, n_embd), and the attention weights, shape: (batch size, n_head, seq len, seq len), if need_weights
+    pub fn forward_self_attention(&mut self, input: &Tensor, padding_mask: Option<&Tensor>, attn_mask: Option<&Tensor>, need_weights: bool) -> (Tensor, Option<Tensor>) {
+        let (q, k, v) = self.project(input, input, input, Some(0));
+        let (ys, weights) = self.attend(&q, &k, &v, padding_mask, attn_mask, true);
+        (self.merge_heads(ys), if need_weights { Some(weights) } else { None })
+    }
+
+    /// Self attention ignoring keys marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_masked(&mut self, input: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        self.forward_self_attention(input, padding_mask, None, false).0
+    }
+
+    /// Attend from input over context (such as encoder outputs) ignoring context positions marked as padding, padding_mask shape: (batch size, context len)
+    pub fn forward_cross(&mut self, input: &Tensor, context: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        self.forward_attention(input, context, context, padding_mask, None, false).0
+    }
+
+    /// Attend from the new positions in input over the cached and new positions, appending the new keys and values to the cache
+    pub fn forward_cached(&mut self, input: &Tensor, cache: &mut KVCache) -> Tensor {
+        let (q, k, v) = self.project(input, input, input, Some(cache.len()));
+        let (k, v) = cache.append(k, v);
+        let (ys, _) = self.attend(&q, &k, &v, None, None, true);
+        self.merge_heads(ys)
+    }
+}
+
+impl Clone for MultiHeadAttention {
+    fn clone(&self) -> Self {
+        MultiHeadAttention {
+            n_head: self.n_head,
+            n_kv_heads: self.n_kv_heads,
+            n_embd: self.n_embd,
+            key: self.key.clone(),
+            query: self.query.clone(),
+            value: self.value.clone(),
+            proj: self.proj.clone(),
+            attn_dropout: self.attn_dropout.clone(),
+            output_dropout: self.output_dropout.clone(),
+            causal_mask: self.causal_mask,
+            positional_encoding: self.positional_encoding.clone(),
+        }
+    }
+}
+
+impl Module for MultiHeadAttention {
+    type Input = tch::Tensor;
+    type Output = tch::Tensor;
+    fn train(&mut self) {
+        self.attn_dropout.train();
+        self.output_dropout.train();
+    }
+
+    fn eval(&mut self) {
+        self.attn_dropout.eval();
+        self.output_dropout.eval();
+    }
+
+    fn forward(&mut self, input: Self::Input) -> Self::Output {
+        self.forward_masked(&input, None)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("key", self.key.named_parameters());
+        parameters.extend(prefix_parameters("query", self.query.named_parameters()));
+        parameters.extend(prefix_parameters("value", self.value.named_parameters()));
+        parameters.extend(prefix_parameters("proj", self.proj.named_parameters()));
+        parameters
+    }
+}
+
+impl ModuleCopy for MultiHeadAttention {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.key.copy(&source.key)?;
+        self.query.copy(&source.query)?;
+        self.value.copy(&source.value)?;
+        self.proj.copy(&source.proj)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,17 @@
-use crate::modules::{LayerNorm, Linear, ModuleCopy, Module, WeightCopyError};
+use crate::modules::{Activation, Dropout, LayerNorm, LayerNormConfig, Linear, RMSNorm, ModuleCopy, Module, WeightCopyError, prefix_parameters};
 use tch::{nn, Device, IndexOp, Kind, Tensor};
+use super::{MultiHeadAttention, MultiHeadAttentionProps};
+use serde::{Deserialize, Serialize};
 
 /// Different types of positional encoding for Transformers
-#[derive(PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum PositionalEncoding {
     Learned,
     Sinusoidal,
+    /// Rotary embeddings (RoPE) applied to queries and keys in attention, not limited by max_len
+    Rotary,
+    /// Per-head linear distance biases (ALiBi) added to the attention scores, not limited by max_len
+    ALiBi,
 }
 
 /// An enum for positional encoding which conains a tensor (only used internally)
@@ -13,129 +19,371 @@ pub enum PositionalEncoding {
 pub(super) enum LocalPositionalEncoding {
     Learned(Tensor),
     Sinusoidal(Tensor),
+    Rotary,
+    ALiBi,
 }
 
-/// The most basic dot-product self attention with an optional causal mask
-#[derive(Debug)]
-pub(crate) struct SelfAttention {
-    n_head: i64,
-    n_embd: i64,
-    dropout: f64,
-    key: Linear,
-    query: Linear,
-    value: Linear,
-    proj: Linear,
-    train: bool,
-    causal_mask: bool
-}
-
-impl SelfAttention {
-    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool) -> Self {
-        SelfAttention {
-            n_embd,
-            n_head,
-            dropout,
-            key: Linear::variance_init(&(p / "key"), n_embd, n_embd),
-            query: Linear::variance_init(&(p / "query"), n_embd, n_embd),
-            value: Linear::variance_init(&(p / "value"), n_embd, n_embd),
-            proj: Linear::variance_init(&(p / "proj"), n_embd, n_embd),
-            train: true,
-            causal_mask,
+impl LocalPositionalEncoding {
+    /// Add the position embeddings for positions offset..offset + seq len to embedded inputs of shape (batch size, seq len, n_embd)
+    pub(super) fn apply(&self, xs: &Tensor, offset: i64) -> Tensor {
+        let (batch_size, seq_len, _) = xs.size3().unwrap();
+        match self {
+            LocalPositionalEncoding::Learned(l) => xs + l.i((.., offset..offset + seq_len, ..)).repeat(&[batch_size, 1, 1]),
+            LocalPositionalEncoding::Sinusoidal(pe) => xs + pe.i(offset..offset + seq_len).repeat(&[batch_size, 1, 1]),
+            // Rotary embeddings and ALiBi biases are applied inside attention instead
+            LocalPositionalEncoding::Rotary | LocalPositionalEncoding::ALiBi => xs.shallow_clone(),
+        }
+    }
+
+    /// The number of positions which can be embedded, None if it isn't limited
+    pub(super) fn max_len(&self) -> Option<i64> {
+        match self {
+            LocalPositionalEncoding::Learned(l) => Some(l.size()[1]),
+            LocalPositionalEncoding::Sinusoidal(pe) => Some(pe.size()[0]),
+            LocalPositionalEncoding::Rotary | LocalPositionalEncoding::ALiBi => None,
+        }
+    }
+
+    /// Only learned position embeddings are parameters
+    pub(super) fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        match self {
+            LocalPositionalEncoding::Learned(l) => vec![("pos_emb".to_string(), l.shallow_clone())],
+            _ => Vec::new(),
         }
     }
+}
+
+/// Rotate (batch, head, seq len, head size) queries or keys by their positions offset..offset + seq len, using the GPT-NeoX / LLaMA layout
+pub(super) fn apply_rotary(xs: &Tensor, offset: i64) -> Tensor {
+    let (_, _, seq_len, head_size) = xs.size4().unwrap();
+    let half = head_size / 2;
+    let device = xs.device();
+    let inv_freq = (Tensor::arange(half, (Kind::Float, device)) * (-2. * f64::ln(10000.) / head_size as f64)).exp();
+    let positions = Tensor::arange_start(offset, offset + seq_len, (Kind::Float, device));
+    // freqs shape: (seq len, head size / 2)
+    let freqs = positions.unsqueeze(1) * inv_freq.unsqueeze(0);
+    let (cos, sin) = (freqs.cos().to_kind(xs.kind()), freqs.sin().to_kind(xs.kind()));
+    let x1 = xs.narrow(-1, 0, half);
+    let x2 = xs.narrow(-1, half, half);
+    Tensor::cat(&[&x1 * &cos - &x2 * &sin, &x2 * &cos + &x1 * &sin], -1)
+}
 
-    fn generate_mask(size: i64, device: Device) -> Tensor{
-        Tensor::ones(&[size, size], (Kind::Float, device)).tril(0).view([1, 1, size, size])
+/// ALiBi head slopes, using the interleaving from the reference implementation when n_head isn't a power of 2
+pub(super) fn alibi_slopes(n_head: i64) -> Vec<f64> {
+    fn power_of_2_slopes(n: i64) -> Vec<f64> {
+        let start = f64::powf(2., -8. / n as f64);
+        (1..=n).map(|i| start.powi(i as i32)).collect()
     }
+    let closest_power_of_2 = 1 << (63 - n_head.leading_zeros());
+    let mut slopes = power_of_2_slopes(closest_power_of_2);
+    slopes.extend(
+        power_of_2_slopes(2 * closest_power_of_2)
+            .into_iter()
+            .step_by(2)
+            .take((n_head - closest_power_of_2) as usize)
+    );
+    slopes
+}
+
+/// ALiBi attention biases of shape (1, n_head, query len, key len), for queries which are the last positions of the keys
+pub(super) fn alibi_bias(n_head: i64, query_len: i64, key_len: i64, device: Device) -> Tensor {
+    let slopes = Tensor::of_slice(&alibi_slopes(n_head)).to_kind(Kind::Float).to(device).view([1, n_head, 1, 1]);
+    let query_positions = Tensor::arange_start(key_len - query_len, key_len, (Kind::Float, device)).view([query_len, 1]);
+    let key_positions = Tensor::arange(key_len, (Kind::Float, device)).view([1, key_len]);
+    (query_positions - key_positions).abs().view([1, 1, query_len, key_len]) * slopes.neg()
 }
 
-impl Clone for SelfAttention {
-    fn clone(&self) -> Self {
-        SelfAttention {
-            n_head: self.n_head,
-            n_embd: self.n_embd,
-            dropout: self.dropout,
-            key: self.key.clone(),
-            query: self.query.clone(),
-            value: self.value.clone(),
-            proj: self.proj.clone(),
-            train: self.train,
-            causal_mask: self.causal_mask,
+/// Cached keys and values of previously seen positions for a single attention layer, used for incremental decoding
+#[derive(Debug, Default)]
+pub struct KVCache {
+    key: Option<Tensor>,
+    value: Option<Tensor>,
+}
+
+impl KVCache {
+    pub fn new() -> Self {
+        KVCache::default()
+    }
+
+    /// The number of cached positions
+    pub fn len(&self) -> i64 {
+        self.key.as_ref().map(|k| k.size()[2]).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.key = None;
+        self.value = None;
+    }
+
+    /// Append new keys and values, shape: (batch size, n_kv_heads, seq len, head size), returning all keys and values
+    pub(super) fn append(&mut self, key: Tensor, value: Tensor) -> (Tensor, Tensor) {
+        let (key, value) = match (&self.key, &self.value) {
+            (Some(k), Some(v)) => (Tensor::cat(&[k, &key], 2), Tensor::cat(&[v, &value], 2)),
+            _ => (key, value),
+        };
+        self.key = Some(key.shallow_clone());
+        self.value = Some(value.shallow_clone());
+        (key, value)
+    }
+}
+
+/// A KV cache for every block in a transformer stack
+#[derive(Debug, Default)]
+pub struct TransformerCache {
+    pub(super) layers: Vec<KVCache>,
+}
+
+impl TransformerCache {
+    pub fn new() -> Self {
+        TransformerCache::default()
+    }
+
+    /// The number of cached positions
+    pub fn len(&self) -> i64 {
+        self.layers.first().map(|l| l.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+}
+
+/// Mask out attention scores (batch, head, query len, key len) for keys where the (batch, key len) padding mask is true.
+/// A large finite value is used rather than -inf so fully padded rows don't produce NaNs which would leak into later layers
+pub(super) fn mask_padding(att: Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+    match padding_mask {
+        Some(mask) => {
+            let (sz_b, sz_k) = mask.size2().unwrap();
+            att.masked_fill(&mask.to_kind(Kind::Bool).view([sz_b, 1, 1, sz_k]), -1e9)
+        },
+        None => att,
+    }
+}
+
+/// Settings for the layers inside each transformer block
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockConfig {
+    /// Hidden size of the feed forward layers, 2 * n_embd if None
+    pub ffn_dim: Option<i64>,
+    /// Activation used in the feed forward layers
+    pub activation: Activation,
+    /// Multiply the activation by a learned gate (SwiGLU with SiLU, GeGLU with GeLU), which doubles the width of the first feed forward layer
+    pub gated: bool,
+    /// Where the norms go relative to the residual connections. The default, Legacy, is neither pre-norm nor post-norm and is only kept so existing models compute the same outputs.
+    /// Deserialized configs default to Pre
+    #[serde(default = "pre_norm_position")]
+    pub norm_position: NormPosition,
+    /// The normalization layer used in blocks and after the last block
+    pub norm: NormKind,
+    /// Epsilon added to the variance (or mean square) by the normalization layers, 1e-5 if None
+    pub norm_eps: Option<f64>,
+    /// Number of key/value heads for grouped-query attention (1 for multi-query attention), must divide n_head. n_head if None
+    pub n_kv_heads: Option<i64>,
+    /// Dropout on the attention weights, the model's dropout if None
+    pub attention_dropout: Option<f64>,
+    /// Dropout on the attention and feed forward outputs before they're added to the residual stream, the model's dropout if None
+    pub residual_dropout: Option<f64>,
+    /// Dropout on the embeddings before the first block, the model's dropout if None
+    pub embedding_dropout: Option<f64>,
+}
+
+fn pre_norm_position() -> NormPosition {
+    NormPosition::Pre
+}
+
+impl BlockConfig {
+    /// The default settings with pre-norm blocks, which model configs (ex. LanguageModelConfig) use when no block is given
+    pub fn pre_norm() -> Self {
+        BlockConfig {
+            norm_position: NormPosition::Pre,
+            ..Default::default()
         }
     }
 }
 
-impl Module for SelfAttention {
-    type Input = tch::Tensor;
-    type Output = tch::Tensor;
-    fn train(&mut self) {
-        self.train = true;
+/// Normalization layers transformers can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NormKind {
+    #[default]
+    LayerNorm,
+    RMSNorm,
+}
+
+/// A normalization layer chosen by NormKind (only used internally)
+#[derive(Debug)]
+pub(super) enum Norm {
+    LayerNorm(LayerNorm),
+    RMSNorm(RMSNorm),
+}
+
+impl Norm {
+    /// A norm of the kind and epsilon set in the block config
+    pub(super) fn new(p: nn::Path, n_embd: i64, config: &BlockConfig) -> Self {
+        let eps = config.norm_eps.unwrap_or(1e-5);
+        match config.norm {
+            NormKind::LayerNorm => Norm::LayerNorm(LayerNorm::new(p, vec![n_embd], LayerNormConfig { eps, ..Default::default() })),
+            NormKind::RMSNorm => Norm::RMSNorm(RMSNorm::new(p, vec![n_embd], eps)),
+        }
     }
 
-    fn eval(&mut self) {
-        self.train = false;
+    pub(super) fn forward(&mut self, x: Tensor) -> Tensor {
+        match self {
+            Norm::LayerNorm(norm) => norm.forward(x),
+            Norm::RMSNorm(norm) => norm.forward(x),
+        }
     }
 
-    fn forward(&mut self, input: Self::Input) -> Self::Output {
-        let (sz_b, sz_t, sz_c) = input.size3().unwrap();
-        let sizes = [sz_b, sz_t, self.n_head, sz_c / self.n_head];
-        let device = input.device();
-        let k = self.key.forward(input.shallow_clone()).view(sizes).transpose(1, 2);
-        let q = self.query.forward(input.shallow_clone()).view(sizes).transpose(1, 2);
-        let v = self.value.forward(input.shallow_clone()).view(sizes).transpose(1, 2);
-        let mut att = q.matmul(&k.transpose(-2, -1)) * (1.0 / f64::sqrt(sizes[3] as f64));
-        if self.causal_mask {
-            let mask = SelfAttention::generate_mask(sz_t, device);
-            att = att.masked_fill(
-                &mask.i((.., .., ..sz_t, ..sz_t)).eq(0.),
-                std::f64::NEG_INFINITY,
-            );
+    pub(super) fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        match self {
+            Norm::LayerNorm(norm) => norm.named_parameters(),
+            Norm::RMSNorm(norm) => norm.named_parameters(),
         }
-        att = att.softmax(-1, Kind::Float).dropout(self.dropout, self.train);
-        let ys = att
-            .matmul(&v)
-            .transpose(1, 2)
-            .contiguous()
-            .view([sz_b, sz_t, sz_c]);
-        self.proj.forward(ys).dropout(self.dropout, self.train)
     }
 }
 
-impl ModuleCopy for SelfAttention {
+impl ModuleCopy for Norm {
     fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
-        self.key.copy(&source.key)?;
-        self.query.copy(&source.query)?;
-        self.value.copy(&source.value)?;
-        self.proj.copy(&source.proj)?;
-        Ok(())
+        match (self, source) {
+            (Norm::LayerNorm(norm), Norm::LayerNorm(source)) => norm.copy(source),
+            (Norm::RMSNorm(norm), Norm::RMSNorm(source)) => norm.copy(source),
+            _ => Err(WeightCopyError::Other("Norms are of different types!".to_string())),
+        }
     }
 }
 
-/// A basic transformer encoder block
+/// Where transformer blocks normalize
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NormPosition {
+    /// Deprecated: the layout of earlier versions of this crate, `x + norm(attention(x))` then `x + ffn(norm(x))`, with decoder blocks normalizing after the cross attention and feed forward residuals.
+    /// This is neither pre-norm nor post-norm, it's the default so existing models and checkpoints keep computing the same outputs, new models should pick Pre or Post
+    #[default]
+    Legacy,
+    /// Normalize the input of each sublayer, `x + sublayer(norm(x))` (as in GPT-2), which is more stable for deep models
+    Pre,
+    /// Normalize after each residual connection, `norm(x + sublayer(x))` (as in the original transformer)
+    Post,
+}
+
+/// The position-wise feed forward layers of a transformer block
 #[derive(Debug)]
-pub struct TransformerBlock {
-    norm1: LayerNorm,
-    norm2: LayerNorm,
-    attn: SelfAttention,
+pub(super) struct FeedForward {
     linear1: Linear,
     linear2: Linear,
-    dropout: f64,
-    train: bool,
+    activation: Activation,
+    gated: bool,
+}
+
+impl FeedForward {
+    /// `linear` creates each linear layer from a path, input size and output size, so blocks can choose how they're initialized
+    pub(super) fn new(p: &nn::Path, n_embd: i64, config: &BlockConfig, linear: fn(&nn::Path, i64, i64) -> Linear) -> Self {
+        let ffn_dim = config.ffn_dim.unwrap_or(2 * n_embd);
+        FeedForward {
+            linear1: linear(&(p / "lin1"), n_embd, if config.gated { 2 * ffn_dim } else { ffn_dim }),
+            linear2: linear(&(p / "lin2"), ffn_dim, n_embd),
+            activation: config.activation,
+            gated: config.gated,
+        }
+    }
+
+    pub(super) fn forward(&mut self, x: Tensor) -> Tensor {
+        let hidden = self.linear1.forward(x);
+        let hidden = if self.gated {
+            let chunks = hidden.chunk(2, -1);
+            self.activation.forward(chunks[0].shallow_clone()) * &chunks[1]
+        } else {
+            self.activation.forward(hidden)
+        };
+        self.linear2.forward(hidden)
+    }
+
+    pub(super) fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("lin1", self.linear1.named_parameters());
+        parameters.extend(prefix_parameters("lin2", self.linear2.named_parameters()));
+        parameters
+    }
+}
+
+impl ModuleCopy for FeedForward {
+    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        self.linear1.copy(&source.linear1)?;
+        self.linear2.copy(&source.linear2)
+    }
+}
+
+/// A basic transformer encoder block
+#[derive(Debug)]
+pub struct TransformerBlock {
+    norm1: Norm,
+    norm2: Norm,
+    attn: MultiHeadAttention,
+    feed_forward: FeedForward,
+    dropout: Dropout,
+    norm_position: NormPosition,
 }
 
 impl TransformerBlock {
-    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool) -> Self {
+    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool, positional_encoding: PositionalEncoding, config: BlockConfig) -> Self {
         assert!(n_embd % n_head == 0, "Embedding size ({}) must be divisible by number of heads ({})!", n_embd, n_head);
         TransformerBlock {
-            norm1: LayerNorm::new(p / "ln1", vec![n_embd]),
-            norm2: LayerNorm::new(p / "ln2", vec![n_embd]),
-            attn: SelfAttention::new(&(p / "attn"), n_embd, n_head, dropout, causal_mask),
-            linear1: Linear::variance_init(&(p / "lin1"), n_embd, 2 * n_embd),
-            linear2: Linear::variance_init(&(p / "lin2"), 2 * n_embd, n_embd),
-            dropout,
-            train: true
+            norm1: Norm::new(p / "ln1", n_embd, &config),
+            norm2: Norm::new(p / "ln2", n_embd, &config),
+            attn: MultiHeadAttention::with_linear(MultiHeadAttentionProps {
+                p: &(p / "attn"),
+                n_embd,
+                n_head,
+                n_kv_heads: config.n_kv_heads,
+                kdim: None,
+                vdim: None,
+                dropout: config.attention_dropout.unwrap_or(dropout),
+                output_dropout: config.residual_dropout.unwrap_or(dropout),
+                causal_mask,
+                positional_encoding,
+            }, Linear::variance_init),
+            feed_forward: FeedForward::new(p, n_embd, &config, Linear::variance_init),
+            dropout: Dropout::new(config.residual_dropout.unwrap_or(dropout)),
+            norm_position: config.norm_position,
         }
     }
+
+    /// Run the attention and feed forward layers with residual connections, `attend` runs the attention layer
+    fn forward_with(&mut self, input: Tensor, attend: impl FnOnce(&mut MultiHeadAttention, &Tensor) -> Tensor) -> Tensor {
+        match self.norm_position {
+            NormPosition::Pre => {
+                let x = &input + attend(&mut self.attn, &self.norm1.forward(input.shallow_clone()));
+                let ys = self.dropout.forward(self.feed_forward.forward(self.norm2.forward(x.shallow_clone())));
+                x + ys
+            },
+            NormPosition::Post => {
+                let x = self.norm1.forward(&input + attend(&mut self.attn, &input));
+                let ys = self.dropout.forward(self.feed_forward.forward(x.shallow_clone()));
+                self.norm2.forward(x + ys)
+            },
+            NormPosition::Legacy => {
+                let x = &input + self.norm1.forward(attend(&mut self.attn, &input));
+                let ys = self.dropout.forward(self.feed_forward.forward(self.norm2.forward(x.shallow_clone())));
+                x + ys
+            },
+        }
+    }
+
+    /// Run the block ignoring positions marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_masked(&mut self, input: Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        self.forward_with(input, |attn, x| attn.forward_masked(x, padding_mask))
+    }
+
+    /// Run the block over new positions only, attending over the cached positions
+    pub fn forward_cached(&mut self, input: Tensor, cache: &mut KVCache) -> Tensor {
+        self.forward_with(input, |attn, x| attn.forward_cached(x, cache))
+    }
 }
 
 impl Module for TransformerBlock {
@@ -144,22 +392,24 @@ impl Module for TransformerBlock {
 
     fn train(&mut self) {
         self.attn.train();
-        self.train = true;
+        self.dropout.train();
     }
 
     fn eval(&mut self) {
         self.attn.eval();
-        self.train = false;
+        self.dropout.eval();
     }
 
     fn forward(&mut self, input: Self::Input) -> Self::Output {
-        let x = input.shallow_clone() + self.norm1.forward(self.attn.forward(input));
-        let ys = self.linear2.forward(
-                self.linear1.forward(
-                    self.norm2.forward(x.shallow_clone())
-                ).gelu()
-            ).dropout(self.dropout, self.train);
-        x + ys
+        self.forward_masked(input, None)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("ln1", self.norm1.named_parameters());
+        parameters.extend(prefix_parameters("ln2", self.norm2.named_parameters()));
+        parameters.extend(prefix_parameters("attn", self.attn.named_parameters()));
+        parameters.extend(self.feed_forward.named_parameters());
+        parameters
     }
 }
 
@@ -168,7 +418,6 @@ impl ModuleCopy for TransformerBlock {
         self.attn.copy(&source.attn)?;
         self.norm1.copy(&source.norm1)?;
         self.norm2.copy(&source.norm2)?;
-        self.linear1.copy(&source.linear1)?;
-        self.linear2.copy(&source.linear2)
+        self.feed_forward.copy(&source.feed_forward)
     }
 }
\ No newline at end of file
@@ -1,133 +1,79 @@
-use crate::modules::{Embedding, LayerNorm, Linear, ModuleCopy, Module, WeightCopyError, PositionalEncoding};
-use tch::{nn, IndexOp, Kind, Tensor, Device};
-use super::{LocalPositionalEncoding, SelfAttention};
-
-/// The most basic dot-product self attention with an optional causal mask
-#[derive(Debug)]
-pub(crate) struct DecoderSelfAttention {
-    n_head: i64,
-    n_embd: i64,
-    dropout: f64,
-    key: Linear,
-    query: Linear,
-    value: Linear,
-    proj: Linear,
-    train: bool,
-    causal_mask: bool
-}
-
-impl DecoderSelfAttention {
-    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool) -> Self {
-        DecoderSelfAttention {
-            n_embd,
-            n_head,
-            dropout,
-            key: Linear::new(&(p / "key"), n_embd, n_embd),
-            query: Linear::new(&(p / "query"), n_embd, n_embd),
-            value: Linear::new(&(p / "value"), n_embd, n_embd),
-            proj: Linear::new(&(p / "proj"), n_embd, n_embd),
-            train: true,
-            causal_mask,
-        }
-    }
-
-    fn generate_mask(size: i64, device: Device) -> Tensor{
-        Tensor::ones(&[size, size], (Kind::Float, device)).tril(0).view([1, 1, size, size])
-    }
-}
-
-impl Clone for DecoderSelfAttention {
-    fn clone(&self) -> Self {
-        DecoderSelfAttention {
-            n_head: self.n_head,
-            n_embd: self.n_embd,
-            dropout: self.dropout,
-            key: self.key.clone(),
-            query: self.query.clone(),
-            value: self.value.clone(),
-            proj: self.proj.clone(),
-            train: self.train,
-            causal_mask: self.causal_mask,
-        }
-    }
-}
-
-impl Module for DecoderSelfAttention {
-    type Input = (tch::Tensor, tch::Tensor);
-    type Output = tch::Tensor;
-    fn train(&mut self) {
-        self.train = true;
-    }
-
-    fn eval(&mut self) {
-        self.train = false;
-    }
-
-    fn forward(&mut self, input: Self::Input) -> Self::Output {
-        let (input, encoder_output) = input;
-        let (sz_b, sz_t, sz_c) = input.size3().unwrap();
-        let (enc_sz_b, enc_sz_t, enc_sz_c) = encoder_output.size3().unwrap();
-        let sizes = [sz_b, sz_t, self.n_head, sz_c / self.n_head];
-        let enc_sizes = [enc_sz_b, enc_sz_t, self.n_head, enc_sz_c / self.n_head];
-        let device = input.device();
-        let k = self.key.forward(encoder_output.shallow_clone()).view(enc_sizes).transpose(1, 2);
-        let q = self.query.forward(input.shallow_clone()).view(sizes).transpose(1, 2);
-        let v = self.value.forward(encoder_output.shallow_clone()).view(enc_sizes).transpose(1, 2);
-        let mut att = q.matmul(&k.transpose(-2, -1)) * (1.0 / f64::sqrt(sizes[3] as f64));
-        if self.causal_mask {
-            let mask = DecoderSelfAttention::generate_mask(sz_t, device);
-            att = att.masked_fill(
-                &mask.i((.., .., ..sz_t, ..sz_t)).eq(0.),
-                std::f64::NEG_INFINITY,
-            );
-        }
-        att = att.softmax(-1, Kind::Float).dropout(self.dropout, self.train);
-        let ys = att
-            .matmul(&v)
-            .transpose(1, 2)
-            .contiguous()
-            .view([sz_b, sz_t, sz_c]);
-        self.proj.forward(ys).dropout(self.dropout, self.train)
-    }
-}
-
-impl ModuleCopy for DecoderSelfAttention {
-    fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
-        self.key.copy(&source.key)?;
-        self.query.copy(&source.query)?;
-        self.value.copy(&source.value)?;
-        self.proj.copy(&source.proj)?;
-        Ok(())
-    }
-}
+use crate::modules::{BlockConfig, Dropout, Embedding, Linear, ModuleCopy, Module, WeightCopyError, PositionalEncoding, prefix_parameters};
+use tch::{nn, Kind, Tensor};
+use super::{FeedForward, LocalPositionalEncoding, MultiHeadAttention, MultiHeadAttentionProps, Norm, NormPosition};
+use serde::{Deserialize, Serialize};
 
 /// A basic transformer decoder block
 #[derive(Debug)]
 pub struct TransformerDecoderBlock {
-    norm1: LayerNorm,
-    norm2: LayerNorm,
-    norm3: LayerNorm,
-    attn: SelfAttention,
-    attn2: DecoderSelfAttention,
-    linear1: Linear,
-    linear2: Linear,
-    dropout: f64,
-    train: bool,
+    norm1: Norm,
+    norm2: Norm,
+    norm3: Norm,
+    attn: MultiHeadAttention,
+    attn2: MultiHeadAttention,
+    feed_forward: FeedForward,
+    dropout: Dropout,
+    norm_position: NormPosition,
 }
 
 impl TransformerDecoderBlock {
-    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool) -> Self {
+    pub fn new(p: &nn::Path, n_embd: i64, n_head: i64, dropout: f64, causal_mask: bool, positional_encoding: PositionalEncoding, config: BlockConfig) -> Self {
         assert!(n_embd % n_head == 0, "Embedding size ({}) must be divisible by number of heads ({})!", n_embd, n_head);
         TransformerDecoderBlock {
-            norm1: LayerNorm::new(p / "ln1", vec![n_embd]),
-            norm2: LayerNorm::new(p / "ln2", vec![n_embd]),
-            norm3: LayerNorm::new(p / "ln3", vec![n_embd]),
-            attn: SelfAttention::new(&(p / "attn"), n_embd, n_head, dropout, causal_mask),
-            attn2: DecoderSelfAttention::new(&(p / "attn2"), n_embd, n_head, dropout, false),
-            linear1: Linear::new(&(p / "lin1"), n_embd, 2 * n_embd),
-            linear2: Linear::new(&(p / "lin2"), 2 * n_embd, n_embd),
-            dropout,
-            train: true
+            norm1: Norm::new(p / "ln1", n_embd, &config),
+            norm2: Norm::new(p / "ln2", n_embd, &config),
+            norm3: Norm::new(p / "ln3", n_embd, &config),
+            attn: MultiHeadAttention::with_linear(MultiHeadAttentionProps {
+                p: &(p / "attn"),
+                n_embd,
+                n_head,
+                n_kv_heads: config.n_kv_heads,
+                kdim: None,
+                vdim: None,
+                dropout: config.attention_dropout.unwrap_or(dropout),
+                output_dropout: config.residual_dropout.unwrap_or(dropout),
+                causal_mask,
+                positional_encoding: positional_encoding.clone(),
+            }, Linear::variance_init),
+            attn2: MultiHeadAttention::new(MultiHeadAttentionProps {
+                p: &(p / "attn2"),
+                n_embd,
+                n_head,
+                n_kv_heads: config.n_kv_heads,
+                kdim: None,
+                vdim: None,
+                dropout: config.attention_dropout.unwrap_or(dropout),
+                output_dropout: config.residual_dropout.unwrap_or(dropout),
+                causal_mask: false,
+                positional_encoding,
+            }),
+            feed_forward: FeedForward::new(p, n_embd, &config, Linear::new),
+            dropout: Dropout::new(config.residual_dropout.unwrap_or(dropout)),
+            norm_position: config.norm_position,
+        }
+    }
+
+    /// Run the block ignoring padded positions, target_padding_mask shape: (batch size, seq len), encoder_padding_mask shape: (batch size, encoder seq len)
+    pub fn forward_masked(&mut self, input: Tensor, encoder_output: &Tensor, target_padding_mask: Option<&Tensor>, encoder_padding_mask: Option<&Tensor>) -> Tensor {
+        match self.norm_position {
+            NormPosition::Pre => {
+                let x = &input + self.attn.forward_masked(&self.norm1.forward(input.shallow_clone()), target_padding_mask);
+                let x = &x + self.attn2.forward_cross(&self.norm2.forward(x.shallow_clone()), encoder_output, encoder_padding_mask);
+                let ys = self.dropout.forward(self.feed_forward.forward(self.norm3.forward(x.shallow_clone())));
+                x + ys
+            },
+            NormPosition::Post => {
+                let x = self.norm1.forward(&input + self.attn.forward_masked(&input, target_padding_mask));
+                let x = self.norm2.forward(&x + self.attn2.forward_cross(&x, encoder_output, encoder_padding_mask));
+                let ys = self.dropout.forward(self.feed_forward.forward(x.shallow_clone()));
+                self.norm3.forward(x + ys)
+            },
+            NormPosition::Legacy => {
+                let x = &input + self.norm1.forward(self.attn.forward_masked(&input, target_padding_mask));
+                let x = self.norm2.forward(&x + self.attn2.forward_cross(&x, encoder_output, encoder_padding_mask));
+                let ys = self.feed_forward.forward(x.shallow_clone());
+                self.dropout.forward(self.norm3.forward(x + ys))
+            },
         }
     }
 }
@@ -138,26 +84,29 @@ impl Module for TransformerDecoderBlock {
 
     fn train(&mut self) {
         self.attn.train();
-        self.train = true;
+        self.attn2.train();
+        self.dropout.train();
     }
 
     fn eval(&mut self) {
         self.attn.eval();
-        self.train = false;
+        self.attn2.eval();
+        self.dropout.eval();
     }
 
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         let (input, encoder_output) = input;
-        let x = input.shallow_clone() + self.norm1.forward(self.attn.forward(input));
-
-        let x = self.norm2.forward(x.shallow_clone() + self.attn2.forward((x, encoder_output)));
+        self.forward_masked(input, &encoder_output, None, None)
+    }
 
-        self.norm3.forward(
-            x.shallow_clone() + 
-            self.linear2.forward(
-                self.linear1.forward(x).gelu()
-            )
-        ).dropout(self.dropout, self.train)
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("ln1", self.norm1.named_parameters());
+        parameters.extend(prefix_parameters("ln2", self.norm2.named_parameters()));
+        parameters.extend(prefix_parameters("ln3", self.norm3.named_parameters()));
+        parameters.extend(prefix_parameters("attn", self.attn.named_parameters()));
+        parameters.extend(prefix_parameters("attn2", self.attn2.named_parameters()));
+        parameters.extend(self.feed_forward.named_parameters());
+        parameters
     }
 }
 
@@ -168,20 +117,18 @@ impl ModuleCopy for TransformerDecoderBlock {
         self.norm1.copy(&source.norm1)?;
         self.norm2.copy(&source.norm2)?;
         self.norm3.copy(&source.norm3)?;
-        self.linear1.copy(&source.linear1)?;
-        self.linear2.copy(&source.linear2)
+        self.feed_forward.copy(&source.feed_forward)
     }
 }
 
 /// A simple autoregressive transformer decoder
 #[derive(Debug)]
 pub struct TransformerDecoder {
-    token_embedding: Embedding,
+    pub(super) token_embedding: Embedding,
     position_embedding: LocalPositionalEncoding,
-    layernorm: LayerNorm,
+    layernorm: Norm,
     blocks: Vec<TransformerDecoderBlock>,
-    dropout: f64,
-    train: bool,
+    dropout: Dropout,
 }
 
 pub struct TransformerDecoderProps<'a> {
@@ -193,19 +140,59 @@ pub struct TransformerDecoderProps<'a> {
     pub positional_encoding: PositionalEncoding, 
     pub max_len: i64, 
     pub dropout: f64, 
-    pub causal_mask: bool
+    pub causal_mask: bool,
+    pub block: BlockConfig,
+}
+
+/// An owned, serializable version of TransformerDecoderProps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransformerDecoderConfig {
+    pub n_embd: i64,
+    pub n_head: i64,
+    pub n_layers: i64,
+    pub vocab_size: i64,
+    pub positional_encoding: PositionalEncoding,
+    pub max_len: i64,
+    pub dropout: f64,
+    pub causal_mask: bool,
+    #[serde(default = "BlockConfig::pre_norm")]
+    pub block: BlockConfig,
+}
+
+impl TransformerDecoderConfig {
+    /// Build the decoder under the path
+    pub fn build(&self, p: &nn::Path) -> TransformerDecoder {
+        TransformerDecoder::new(TransformerDecoderProps {
+            p,
+            n_embd: self.n_embd,
+            n_head: self.n_head,
+            n_layers: self.n_layers,
+            vocab_size: self.vocab_size,
+            positional_encoding: self.positional_encoding.clone(),
+            max_len: self.max_len,
+            dropout: self.dropout,
+            causal_mask: self.causal_mask,
+            block: self.block.clone(),
+        })
+    }
 }
 
 impl TransformerDecoder {
     #[allow(clippy::too_many_arguments)]
     pub fn new(props: TransformerDecoderProps) -> Self {
+        let token_embedding = Embedding::new(
+            props.p / "tok_emb",
+            props.vocab_size,
+            props.n_embd,
+        );
+        Self::with_token_embedding(props, token_embedding)
+    }
+
+    /// Build the decoder around an existing token embedding (used to share embeddings with an encoder), no tok_emb variable is created
+    pub(super) fn with_token_embedding(props: TransformerDecoderProps, token_embedding: Embedding) -> Self {
         TransformerDecoder {
-            token_embedding: Embedding::new(
-                props.p / "tok_emb",
-                props.vocab_size,
-                props.n_embd,
-            ),
-            position_embedding: match props.positional_encoding {
+            token_embedding,
+            position_embedding: match props.positional_encoding.clone() {
                 PositionalEncoding::Learned => LocalPositionalEncoding::Learned(props.p.randn("pos_emb", &[1, props.max_len, props.n_embd], 0., 0.5)),
                 PositionalEncoding::Sinusoidal => {
                     // Build the sinusoidal vector (This is based on an online implementation here: https://towardsdatascience.com/how-to-code-the-transformer-in-pytorch-24db27c8f9ec#d554
@@ -217,21 +204,48 @@ impl TransformerDecoder {
                         }
                     }
                     LocalPositionalEncoding::Sinusoidal(Tensor::of_slice2(&pe).to_kind(Kind::Float).to(props.p.device())) // Doesn't need to be a variable, we aren't tracking it's gradients
-                }
+                },
+                PositionalEncoding::Rotary => LocalPositionalEncoding::Rotary,
+                PositionalEncoding::ALiBi => LocalPositionalEncoding::ALiBi,
             },
-            layernorm: LayerNorm::new(props.p / "ln_f", vec![props.n_embd]),
+            layernorm: Norm::new(props.p / "ln_f", props.n_embd, &props.block),
             blocks: {
                 //let p = &p.set_group(0);
                 let mut blocks = Vec::new();
                 for block_idx in 0..props.n_layers {
-                    blocks.push(TransformerDecoderBlock::new(&(props.p / block_idx), props.n_embd, props.n_head, props.dropout, props.causal_mask));
+                    blocks.push(TransformerDecoderBlock::new(&(props.p / block_idx), props.n_embd, props.n_head, props.dropout, props.causal_mask, props.positional_encoding.clone(), props.block.clone()));
                 }
                 blocks
             },
-            dropout: props.dropout,
-            train: true,
+            dropout: Dropout::new(props.block.embedding_dropout.unwrap_or(props.dropout)),
         }
     }
+
+    /// Decode ignoring padded positions, target_padding_mask shape: (batch size, seq len), encoder_padding_mask shape: (batch size, encoder seq len)
+    pub fn forward_masked(&mut self, input: &Tensor, encoder_output: &Tensor, target_padding_mask: Option<&Tensor>, encoder_padding_mask: Option<&Tensor>) -> Tensor {
+        // x shape: (batch size, seq len)
+        // Run through embeddings
+        let tok_emb = self.token_embedding.forward(input.shallow_clone());
+        let x = self.dropout.forward(self.position_embedding.apply(&tok_emb, 0));
+        // Run through transformer blocks
+        let x = self.blocks
+            .iter_mut()
+            .fold(x, |x, layer| layer.forward_masked(x, encoder_output, target_padding_mask, encoder_padding_mask));
+        self.layernorm.forward(x)
+        // output shape: (batch size, seq len, n_embd)
+    }
+
+    /// Freeze the first n blocks (closest to the input), leaving the later blocks trainable
+    pub fn freeze_blocks(&mut self, n: usize) {
+        for block in self.blocks.iter_mut().take(n) {
+            block.freeze();
+        }
+    }
+
+    /// The longest sequence the position embeddings cover, None for encodings without a limit
+    pub(super) fn max_len(&self) -> Option<i64> {
+        self.position_embedding.max_len()
+    }
 }
 
 impl Module for TransformerDecoder {
@@ -242,39 +256,29 @@ impl Module for TransformerDecoder {
         for block in &mut self.blocks {
             block.train();
         }
-        self.train = true;
+        self.dropout.train();
     }
 
     fn eval(&mut self) {
         for block in &mut self.blocks {
             block.eval();
         }
-        self.train = false;
+        self.dropout.eval();
     }
 
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         let (input, encoder_output) = input;
-        // x shape: (batch size, seq len)
-        let (batch_size, sz_t) = input.size2().unwrap();
-        // Run through embeddings
-        let tok_emb = self.token_embedding.forward(input);
-        let pos_emb = match &mut self.position_embedding {
-            LocalPositionalEncoding::Learned(l) => l.i((.., ..sz_t, ..)).repeat(&[batch_size, 1, 1]),
-            LocalPositionalEncoding::Sinusoidal(pe) => {
-                pe.i(..sz_t).repeat(&[batch_size, 1, 1])
-            }
-        };
-        let x = (tok_emb + pos_emb)
-            .dropout(self.dropout, self.train);
-        // Run through transformer blocks
-        let x = self.blocks[0].forward((x, encoder_output.shallow_clone()));
-        let x = self.blocks
-            .iter_mut()
-            .skip(1)
-            .fold(x, |x, layer| layer.forward((x, encoder_output.shallow_clone())));
-        // Return first token
-        self.layernorm.forward(x)
-        // output shape: (batch size, n_embd)
+        self.forward_masked(&input, &encoder_output, None, None)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("tok_emb", self.token_embedding.named_parameters());
+        parameters.extend(self.position_embedding.named_parameters());
+        parameters.extend(prefix_parameters("ln_f", self.layernorm.named_parameters()));
+        for (i, block) in self.blocks.iter().enumerate() {
+            parameters.extend(prefix_parameters(&i.to_string(), block.named_parameters()));
+        }
+        parameters
     }
 }
 
@@ -304,7 +308,17 @@ impl ModuleCopy for TransformerDecoder {
                         t.copy_(s);
                     });
                 } else {return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));}
-            }
+            },
+            LocalPositionalEncoding::Rotary => {
+                if !matches!(self.position_embedding, LocalPositionalEncoding::Rotary) {
+                    return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));
+                }
+            },
+            LocalPositionalEncoding::ALiBi => {
+                if !matches!(self.position_embedding, LocalPositionalEncoding::ALiBi) {
+                    return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));
+                }
+            },
         }
 
         self.layernorm.copy(&source.layernorm)?;
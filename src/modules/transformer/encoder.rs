@@ -1,17 +1,17 @@
-use crate::modules::{Embedding, LayerNorm, Linear, ModuleCopy, Module, WeightCopyError, TransformerBlock, PositionalEncoding};
+use crate::modules::{Dropout, Embedding, Linear, ModuleCopy, Module, WeightCopyError, TransformerBlock, PositionalEncoding, BlockConfig, prefix_parameters};
 use tch::{nn, IndexOp, Kind, Tensor};
-use super::LocalPositionalEncoding;
+use super::{KVCache, LocalPositionalEncoding, Norm, TransformerCache};
+use serde::{Deserialize, Serialize};
 
 /// A basic transformer encoder stack using learned embeddings
 #[derive(Debug)]
 pub struct TransformerEncoder {
-    token_embedding: Embedding,
+    pub(super) token_embedding: Embedding,
     position_embedding: LocalPositionalEncoding,
-    layernorm: LayerNorm,
+    layernorm: Norm,
     blocks: Vec<TransformerBlock>,
-    dropout: f64,
+    dropout: Dropout,
     pub(super) n_embed: i64,
-    train: bool,
 }
 
 pub struct TransformerEncoderProps<'a> {
@@ -23,7 +23,41 @@ pub struct TransformerEncoderProps<'a> {
     pub positional_encoding: PositionalEncoding, 
     pub max_len: i64, 
     pub dropout: f64, 
-    pub causal_mask: bool
+    pub causal_mask: bool,
+    pub block: BlockConfig,
+}
+
+/// An owned, serializable version of TransformerEncoderProps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransformerEncoderConfig {
+    pub n_embd: i64,
+    pub n_head: i64,
+    pub n_layers: i64,
+    pub vocab_size: i64,
+    pub positional_encoding: PositionalEncoding,
+    pub max_len: i64,
+    pub dropout: f64,
+    pub causal_mask: bool,
+    #[serde(default = "BlockConfig::pre_norm")]
+    pub block: BlockConfig,
+}
+
+impl TransformerEncoderConfig {
+    /// Build the encoder under the path
+    pub fn build(&self, p: &nn::Path) -> TransformerEncoder {
+        TransformerEncoder::new(TransformerEncoderProps {
+            p,
+            n_embd: self.n_embd,
+            n_head: self.n_head,
+            n_layers: self.n_layers,
+            vocab_size: self.vocab_size,
+            positional_encoding: self.positional_encoding.clone(),
+            max_len: self.max_len,
+            dropout: self.dropout,
+            causal_mask: self.causal_mask,
+            block: self.block.clone(),
+        })
+    }
 }
 
 impl TransformerEncoder {
@@ -34,7 +68,7 @@ impl TransformerEncoder {
                 props.vocab_size,
                 props.n_embd,
             ),
-            position_embedding: match props.positional_encoding {
+            position_embedding: match props.positional_encoding.clone() {
                 PositionalEncoding::Learned => LocalPositionalEncoding::Learned(props.p.randn("pos_emb", &[1, props.max_len, props.n_embd], 0., 0.5)),
                 PositionalEncoding::Sinusoidal => {
                     // Build the sinusoidal vector (This is based on an online implementation here: https://towardsdatascience.com/how-to-code-the-transformer-in-pytorch-24db27c8f9ec#d554
@@ -47,42 +81,69 @@ impl TransformerEncoder {
                     }
                     LocalPositionalEncoding::Sinusoidal(Tensor::of_slice2(&pe).to_kind(Kind::Float).to(props.p.device())) // Doesn't need to be a variable, we aren't tracking it's gradients
                 },
+                PositionalEncoding::Rotary => LocalPositionalEncoding::Rotary,
+                PositionalEncoding::ALiBi => LocalPositionalEncoding::ALiBi,
             },
-            layernorm: LayerNorm::new(props.p / "ln_f", vec![props.n_embd]),
+            layernorm: Norm::new(props.p / "ln_f", props.n_embd, &props.block),
             blocks: {
                 //let p = &p.set_group(0);
                 let mut blocks = Vec::new();
                 for block_idx in 0..props.n_layers {
-                    blocks.push(TransformerBlock::new(&(props.p / block_idx), props.n_embd, props.n_head, props.dropout, props.causal_mask));
+                    blocks.push(TransformerBlock::new(&(props.p / block_idx), props.n_embd, props.n_head, props.dropout, props.causal_mask, props.positional_encoding.clone(), props.block.clone()));
                 }
                 blocks
             },
-            dropout: props.dropout,
+            dropout: Dropout::new(props.block.embedding_dropout.unwrap_or(props.dropout)),
             n_embed: props.n_embd,
-            train: true,
         }
     }
 
-    pub fn forward_no_embed(&mut self, xs: &Tensor) -> Tensor {
+    /// Run already embedded inputs through the encoder, ignoring positions marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_no_embed(&mut self, xs: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
         // xs shape: (batch size, seq len, n_embd)
-        let (batch_size, sz_t, _) = xs.size3().unwrap();
-        let pos_emb = match &mut self.position_embedding {
-            LocalPositionalEncoding::Learned(l) => l.i((.., ..sz_t, ..)).repeat(&[batch_size, 1, 1]),
-            LocalPositionalEncoding::Sinusoidal(pe) => {
-                pe.i(..sz_t).repeat(&[batch_size, 1, 1])
-            },
-        };
-        let mut x = (xs + pos_emb)
-            .dropout(self.dropout, self.train);
+        let x = self.dropout.forward(self.position_embedding.apply(xs, 0));
         // Run through transformer blocks
-        x = self.blocks[0].forward(x);
-        x = self.blocks
+        let x = self.blocks
             .iter_mut()
-            .skip(1)
-            .fold(x, |x, layer| layer.forward(x));
-        // Return first token
+            .fold(x, |x, layer| layer.forward_masked(x, padding_mask));
         self.layernorm.forward(x)
-        // output shape: (batch size, n_embd)
+        // output shape: (batch size, seq len, n_embd)
+    }
+
+    /// Encode tokens ignoring positions marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_masked(&mut self, input: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        // input shape: (batch size, seq len)
+        let tok_emb = self.token_embedding.forward(input.shallow_clone());
+        self.forward_no_embed(&tok_emb, padding_mask)
+    }
+
+    /// Run only the new tokens through the encoder, attending over the positions stored in the cache and appending the new ones
+    pub fn forward_cached(&mut self, input: &Tensor, cache: &mut TransformerCache) -> Tensor {
+        // input shape: (batch size, new seq len)
+        cache.layers.resize_with(self.blocks.len(), KVCache::default);
+        let offset = cache.len();
+        // Run through embeddings, offsetting positions by the cached length
+        let tok_emb = self.token_embedding.forward(input.shallow_clone());
+        let x = self.dropout.forward(self.position_embedding.apply(&tok_emb, offset));
+        // Run through transformer blocks
+        let x = self.blocks
+            .iter_mut()
+            .zip(cache.layers.iter_mut())
+            .fold(x, |x, (layer, layer_cache)| layer.forward_cached(x, layer_cache));
+        self.layernorm.forward(x)
+        // output shape: (batch size, new seq len, n_embd)
+    }
+
+    /// Freeze the first n blocks (closest to the input), leaving the later blocks trainable
+    pub fn freeze_blocks(&mut self, n: usize) {
+        for block in self.blocks.iter_mut().take(n) {
+            block.freeze();
+        }
+    }
+
+    /// The longest sequence the position embeddings cover, None for encodings without a limit
+    pub(super) fn max_len(&self) -> Option<i64> {
+        self.position_embedding.max_len()
     }
 }
 
@@ -94,38 +155,28 @@ impl Module for TransformerEncoder {
         for block in &mut self.blocks {
             block.train();
         }
-        self.train = true;
+        self.dropout.train();
     }
 
     fn eval(&mut self) {
         for block in &mut self.blocks {
             block.eval();
         }
-        self.train = false;
+        self.dropout.eval();
     }
 
     fn forward(&mut self, input: Self::Input) -> Self::Output {
-        // x shape: (batch size, seq len)
-        let (batch_size, sz_t) = input.size2().unwrap();
-        // Run through embeddings
-        let tok_emb = self.token_embedding.forward(input);
-        let pos_emb = match &mut self.position_embedding {
-            LocalPositionalEncoding::Learned(l) => l.i((.., ..sz_t, ..)).repeat(&[batch_size, 1, 1]),
-            LocalPositionalEncoding::Sinusoidal(pe) => {
-                pe.i(..sz_t).repeat(&[batch_size, 1, 1])
-            },
-        };
-        let x = (tok_emb + pos_emb)
-            .dropout(self.dropout, self.train);
-        // Run through transformer blocks
-        let x = self.blocks[0].forward(x);
-        let x = self.blocks
-            .iter_mut()
-            .skip(1)
-            .fold(x, |x, layer| layer.forward(x));
-        // Return first token
-        self.layernorm.forward(x)
-        // output shape: (batch size, n_embd)
+        self.forward_masked(&input, None)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("tok_emb", self.token_embedding.named_parameters());
+        parameters.extend(self.position_embedding.named_parameters());
+        parameters.extend(prefix_parameters("ln_f", self.layernorm.named_parameters()));
+        for (i, block) in self.blocks.iter().enumerate() {
+            parameters.extend(prefix_parameters(&i.to_string(), block.named_parameters()));
+        }
+        parameters
     }
 }
 
@@ -156,6 +207,16 @@ impl ModuleCopy for TransformerEncoder {
                     });
                 } else {return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));}
             },
+            LocalPositionalEncoding::Rotary => {
+                if !matches!(self.position_embedding, LocalPositionalEncoding::Rotary) {
+                    return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));
+                }
+            },
+            LocalPositionalEncoding::ALiBi => {
+                if !matches!(self.position_embedding, LocalPositionalEncoding::ALiBi) {
+                    return Err(WeightCopyError::Other("Positional Encodings are of wrong type!".to_string()));
+                }
+            },
         }
 
         self.layernorm.copy(&source.layernorm)?;
@@ -184,7 +245,41 @@ pub struct TransformerAggregatorProps<'a> {
     pub vocab_size: i64, 
     pub positional_encoding: PositionalEncoding, 
     pub max_len: i64, 
-    pub dropout: f64
+    pub dropout: f64,
+    pub block: BlockConfig,
+}
+
+/// An owned, serializable version of TransformerAggregatorProps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransformerAggregatorConfig {
+    pub n_embd: i64,
+    pub n_head: i64,
+    pub n_layers: i64,
+    pub aggregation_size: i64,
+    pub vocab_size: i64,
+    pub positional_encoding: PositionalEncoding,
+    pub max_len: i64,
+    pub dropout: f64,
+    #[serde(default = "BlockConfig::pre_norm")]
+    pub block: BlockConfig,
+}
+
+impl TransformerAggregatorConfig {
+    /// Build the aggregator under the path
+    pub fn build(&self, p: &nn::Path) -> TransformerAggregator {
+        TransformerAggregator::new(TransformerAggregatorProps {
+            p,
+            n_embd: self.n_embd,
+            n_head: self.n_head,
+            n_layers: self.n_layers,
+            aggregation_size: self.aggregation_size,
+            vocab_size: self.vocab_size,
+            positional_encoding: self.positional_encoding.clone(),
+            max_len: self.max_len,
+            dropout: self.dropout,
+            block: self.block.clone(),
+        })
+    }
 }
 
 impl TransformerAggregator {
@@ -200,7 +295,8 @@ impl TransformerAggregator {
                 positional_encoding: props.positional_encoding, 
                 max_len: props.max_len, 
                 dropout: props.dropout, 
-                causal_mask: false
+                causal_mask: false,
+                block: props.block,
             }),
             head: Linear::new(&(props.p / "aggregation_head"), props.n_embd, props.aggregation_size),
             aggregation_embedding: props.p.randn("aggregation_vector", &[props.n_embd], 0.0, 0.2),
@@ -214,6 +310,27 @@ impl TransformerAggregator {
             encoder
         }
     }
+
+    /// Aggregate a sequence while ignoring positions marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_masked(&mut self, x: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        // xs shape: (batch size, seq len)
+        let batch_size = x.size()[0];
+        // Embed and append aggregation embedding to beginning
+        let xs = tch::Tensor::cat(&[
+            &self.aggregation_embedding.unsqueeze(0).unsqueeze(0).repeat(&[batch_size, 1, 1]), 
+            &self.encoder.token_embedding.forward(x.shallow_clone())
+        ], 1);
+        // The aggregation token is never padding
+        let padding_mask = padding_mask.map(|mask| Tensor::cat(&[
+            &Tensor::zeros(&[batch_size, 1], (Kind::Bool, mask.device())),
+            &mask.to_kind(Kind::Bool)
+        ], 1));
+        // Run through encoder
+        let xs = self.encoder.forward_no_embed(&xs, padding_mask.as_ref());
+        // Return first token
+        self.head.forward(xs.i((.., 0, ..)).squeeze_dim(1))
+        // output shape: (batch size, n_embd)
+    }
 }
 
 impl Module for TransformerAggregator {
@@ -229,18 +346,14 @@ impl Module for TransformerAggregator {
     }
 
     fn forward(&mut self, x: Self::Input) -> Self::Output {
-        // xs shape: (batch size, seq len)
-        let batch_size = x.size()[0];
-        // Embed and append aggregation embedding to beginning
-        let mut xs = tch::Tensor::cat(&[
-            &self.aggregation_embedding.unsqueeze(0).unsqueeze(0).repeat(&[batch_size, 1, 1]), 
-            &self.encoder.token_embedding.forward(x)
-        ], 1);
-        // Run through encoder
-        xs = self.encoder.forward_no_embed(&xs);
-        // Return first token
-        self.head.forward(xs.i((.., 0, ..)).squeeze_dim(1))
-        // output shape: (batch size, n_embd)
+        self.forward_masked(&x, None)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("encoder", self.encoder.named_parameters());
+        parameters.push(("aggregation_vector".to_string(), self.aggregation_embedding.shallow_clone()));
+        parameters.extend(prefix_parameters("aggregation_head", self.head.named_parameters()));
+        parameters
     }
 }
 
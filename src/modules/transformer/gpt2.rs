@@ -0,0 +1,106 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tch::{nn, Tensor};
+use crate::checkpoint::{self, CheckpointError};
+use crate::modules::{Activation, BlockConfig, LanguageModel, LanguageModelConfig, Module, NormPosition, PositionalEncoding, StateDict, StateDictError, StateDictKeys};
+
+/// The shape of a GPT-2 model, using the same names as the Hugging Face config.json so it can be read directly.
+/// The defaults are the smallest (124M) GPT-2.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GPT2Config {
+    pub n_embd: i64,
+    pub n_head: i64,
+    pub n_layer: i64,
+    pub vocab_size: i64,
+    pub n_positions: i64,
+}
+
+impl Default for GPT2Config {
+    fn default() -> Self {
+        GPT2Config {
+            n_embd: 768,
+            n_head: 12,
+            n_layer: 12,
+            vocab_size: 50257,
+            n_positions: 1024,
+        }
+    }
+}
+
+impl GPT2Config {
+    /// The LanguageModel config with the GPT-2 layout (pre-norm blocks with 4x feed forward layers using tanh GeLU, and a head tied to the token embedding)
+    pub fn language_model_config(&self) -> LanguageModelConfig {
+        LanguageModelConfig {
+            n_embd: self.n_embd,
+            n_head: self.n_head,
+            n_layers: self.n_layer,
+            vocab_size: self.vocab_size,
+            positional_encoding: PositionalEncoding::Learned,
+            max_len: self.n_positions,
+            dropout: 0.1,
+            block: BlockConfig {
+                ffn_dim: Some(4 * self.n_embd),
+                activation: Activation::GeLUTanh,
+                norm_position: NormPosition::Pre,
+                ..Default::default()
+            },
+            tie_embeddings: true,
+        }
+    }
+}
+
+/// Read GPT-2 weights (Hugging Face names, from a safetensors or npz file) and rename them to match a LanguageModel built from `config.language_model_config()`
+pub fn read_gpt2_state_dict<T: AsRef<Path>>(config: &GPT2Config, path: T) -> Result<StateDict, CheckpointError> {
+    let path = path.as_ref();
+    let tensors: StateDict = if path.extension().map_or(false, |ext| ext == "npz") {
+        Tensor::read_npz(path)?.into_iter().collect()
+    } else {
+        checkpoint::read(path)?.0
+    };
+    // GPT2LMHeadModel checkpoints prefix everything with "transformer."
+    let mut tensors: StateDict = tensors.into_iter()
+        .map(|(name, tensor)| (name.strip_prefix("transformer.").map(str::to_string).unwrap_or(name), tensor))
+        .collect();
+    let mut take = |name: String| tensors.remove(&name).ok_or_else(|| CheckpointError::StateDict(StateDictError::KeyMismatch(StateDictKeys {
+        missing: vec![name],
+        unexpected: vec![],
+    })));
+
+    let mut state_dict = StateDict::new();
+    let wte = take("wte.weight".to_string())?;
+    // The output head is tied to the token embedding, GPT-2's head has no bias
+    state_dict.insert("lm_head.bias".to_string(), Tensor::zeros(&[config.vocab_size], (wte.kind(), wte.device())));
+    state_dict.insert("transformer.tok_emb.weight".to_string(), wte);
+    state_dict.insert("transformer.pos_emb".to_string(), take("wpe.weight".to_string())?.unsqueeze(0));
+    state_dict.insert("transformer.ln_f.weight".to_string(), take("ln_f.weight".to_string())?);
+    state_dict.insert("transformer.ln_f.bias".to_string(), take("ln_f.bias".to_string())?);
+    for layer in 0..config.n_layer {
+        let (source, target) = (format!("h.{}", layer), format!("transformer.{}", layer));
+        for (source_name, target_name) in [("ln_1", "ln1"), ("ln_2", "ln2")] {
+            state_dict.insert(format!("{}.{}.weight", target, target_name), take(format!("{}.{}.weight", source, source_name))?);
+            state_dict.insert(format!("{}.{}.bias", target, target_name), take(format!("{}.{}.bias", source, source_name))?);
+        }
+        // GPT-2 uses Conv1D layers which store weights as (in, out), Linear stores (out, in)
+        for (source_name, target_name) in [("attn.c_proj", "attn.proj"), ("mlp.c_fc", "lin1"), ("mlp.c_proj", "lin2")] {
+            state_dict.insert(format!("{}.{}.weight", target, target_name), take(format!("{}.{}.weight", source, source_name))?.tr().contiguous());
+            state_dict.insert(format!("{}.{}.bias", target, target_name), take(format!("{}.{}.bias", source, source_name))?);
+        }
+        // Split the fused query, key and value projection
+        let qkv_weight = take(format!("{}.attn.c_attn.weight", source))?.tr();
+        let qkv_bias = take(format!("{}.attn.c_attn.bias", source))?;
+        for (i, name) in ["query", "key", "value"].iter().enumerate() {
+            let start = i as i64 * config.n_embd;
+            state_dict.insert(format!("{}.attn.{}.weight", target, name), qkv_weight.narrow(0, start, config.n_embd).contiguous());
+            state_dict.insert(format!("{}.attn.{}.bias", target, name), qkv_bias.narrow(0, start, config.n_embd));
+        }
+    }
+    // Anything left (attention mask buffers, the tied lm_head.weight) isn't needed
+    Ok(state_dict)
+}
+
+/// Build a LanguageModel with the GPT-2 layout under the path and load GPT-2 weights into it
+pub fn load_gpt2<T: AsRef<Path>>(p: &nn::Path, config: &GPT2Config, path: T) -> Result<LanguageModel, CheckpointError> {
+    let mut model = config.language_model_config().build(p);
+    model.load_state_dict(&read_gpt2_state_dict(config, path)?, true)?;
+    Ok(model)
+}
@@ -1,5 +1,8 @@
-use crate::modules::{Linear, ModuleCopy, Module, WeightCopyError, TransformerEncoder, TransformerEncoderProps, PositionalEncoding};
-use tch::nn;
+use crate::modules::{Linear, ModuleCopy, Module, WeightCopyError, TransformerEncoder, TransformerEncoderProps, TransformerCache, PositionalEncoding, BlockConfig, prefix_parameters};
+use crate::sampling::Sampler;
+use rand::Rng;
+use tch::{nn, Kind, Tensor};
+use serde::{Deserialize, Serialize};
 
 /// A simple language model, using a causally masked transformer encoder and a head
 #[derive(Debug)]
@@ -16,7 +19,44 @@ pub struct LanguageModelProps<'a> {
     pub vocab_size: i64, 
     pub positional_encoding: PositionalEncoding, 
     pub max_len: i64, 
-    pub dropout: f64
+    pub dropout: f64,
+    pub block: BlockConfig,
+    /// Use the token embedding as the weight of the output head
+    pub tie_embeddings: bool,
+}
+
+/// An owned, serializable version of LanguageModelProps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LanguageModelConfig {
+    pub n_embd: i64,
+    pub n_head: i64,
+    pub n_layers: i64,
+    pub vocab_size: i64,
+    pub positional_encoding: PositionalEncoding,
+    pub max_len: i64,
+    pub dropout: f64,
+    #[serde(default = "BlockConfig::pre_norm")]
+    pub block: BlockConfig,
+    #[serde(default)]
+    pub tie_embeddings: bool,
+}
+
+impl LanguageModelConfig {
+    /// Build the language model under the path
+    pub fn build(&self, p: &nn::Path) -> LanguageModel {
+        LanguageModel::new(LanguageModelProps {
+            p,
+            n_embd: self.n_embd,
+            n_head: self.n_head,
+            n_layers: self.n_layers,
+            vocab_size: self.vocab_size,
+            positional_encoding: self.positional_encoding.clone(),
+            max_len: self.max_len,
+            dropout: self.dropout,
+            block: self.block.clone(),
+            tie_embeddings: self.tie_embeddings,
+        })
+    }
 }
 
 unsafe impl Send for LanguageModel {}
@@ -24,19 +64,26 @@ unsafe impl Sync for LanguageModel {}
 
 impl LanguageModel {
     pub fn new(props: LanguageModelProps) -> Self {
+        let transformer = TransformerEncoder::new(TransformerEncoderProps{
+            p: &(props.p / "transformer"), 
+            n_embd: props.n_embd, 
+            n_head: props.n_head, 
+            n_layers: props.n_layers, 
+            vocab_size: props.vocab_size, 
+            positional_encoding: props.positional_encoding, 
+            max_len: props.max_len, 
+            dropout: props.dropout, 
+            causal_mask: true,
+            block: props.block,
+        });
+        let head = if props.tie_embeddings {
+            Linear::tied(&(props.p / "lm_head"), &transformer.token_embedding.ws)
+        } else {
+            Linear::new(&(props.p / "lm_head"), props.n_embd, props.vocab_size)
+        };
         LanguageModel {
-            transformer: TransformerEncoder::new(TransformerEncoderProps{
-                p: &(props.p / "transformer"), 
-                n_embd: props.n_embd, 
-                n_head: props.n_head, 
-                n_layers: props.n_layers, 
-                vocab_size: props.vocab_size, 
-                positional_encoding: props.positional_encoding, 
-                max_len: props.max_len, 
-                dropout: props.dropout, 
-                causal_mask: true
-            }),
-            head: Linear::new(&(props.p / "lm_head"), props.n_embd, props.vocab_size)
+            transformer,
+            head,
         }
     }
 
@@ -46,6 +93,53 @@ impl LanguageModel {
             transformer: encoder,
         }
     }
+
+    /// Whether the output head uses the token embedding as its weight
+    pub fn tied_embeddings(&self) -> bool {
+        self.head.ws.data_ptr() == self.transformer.token_embedding.ws.data_ptr()
+    }
+
+    /// Get logits for every position while ignoring positions marked as padding, padding_mask shape: (batch size, seq len)
+    pub fn forward_masked(&mut self, input: &Tensor, padding_mask: Option<&Tensor>) -> Tensor {
+        self.head.forward(
+            self.transformer.forward_masked(input, padding_mask)
+        )
+    }
+
+    /// Run only the newest token(s) through the model using a KV cache of the previous positions.
+    /// Returns logits for each new position, the last of which are the next-token logits, shape: (batch size, new seq len, vocab size)
+    pub fn forward_step(&mut self, input: &Tensor, cache: &mut TransformerCache) -> Tensor {
+        self.head.forward(
+            self.transformer.forward_cached(input, cache)
+        )
+    }
+
+    /// Generate tokens following a batch of prompts using a KV cache, stopping each sequence independently (call eval() first to disable dropout).
+    /// prompt shape: (batch size, prompt len), output shape: (batch size, generated len), sequences which stopped early are filled with the pad token
+    pub fn generate<R: Rng>(&mut self, prompt: &Tensor, sampler: &mut Sampler<R>) -> Tensor {
+        tch::no_grad(|| {
+            let (batch_size, prompt_len) = prompt.size2().unwrap();
+            // Every token but the last generated one is run through the model
+            if let Some(max_len) = self.transformer.max_len() {
+                assert!(prompt_len + sampler.config.max_len - 1 <= max_len, "Prompt length ({}) plus tokens to generate ({}) is longer than the model's max_len ({})!", prompt_len, sampler.config.max_len, max_len);
+            }
+            let mut tokens = prompt.to_kind(Kind::Int64);
+            let mut output = Tensor::zeros(&[batch_size, 0], (Kind::Int64, prompt.device()));
+            let mut finished = Tensor::zeros(&[batch_size], (Kind::Bool, prompt.device()));
+            let mut cache = TransformerCache::new();
+            let mut logits = self.forward_step(&tokens, &mut cache).select(1, -1);
+            for step in 0..sampler.config.max_len {
+                // Sequences which stopped keep running on their sampled tokens (the pad token may not be a valid input), they are only padded in the output
+                let next_tokens = sampler.sample(&logits, &tokens, step);
+                output = Tensor::cat(&[&output, &next_tokens.masked_fill(&finished, sampler.config.pad_token).unsqueeze(1)], 1);
+                finished = finished.logical_or(&sampler.is_stop_token(&next_tokens));
+                tokens = Tensor::cat(&[&tokens, &next_tokens.unsqueeze(1)], 1);
+                if finished.all().int64_value(&[]) == 1 || step + 1 == sampler.config.max_len {break;}
+                logits = self.forward_step(&next_tokens.unsqueeze(1), &mut cache).select(1, -1);
+            }
+            output
+        })
+    }
 }
 
 impl Module for LanguageModel {
@@ -67,10 +161,24 @@ impl Module for LanguageModel {
             self.transformer.forward(input)
         )
     }
+
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("transformer", self.transformer.named_parameters());
+        let mut head_parameters = self.head.named_parameters();
+        if self.tied_embeddings() {
+            // The weight is already listed as transformer.tok_emb.weight
+            head_parameters.retain(|(name, _)| name != "weight");
+        }
+        parameters.extend(prefix_parameters("lm_head", head_parameters));
+        parameters
+    }
 }
 
 impl ModuleCopy for LanguageModel {
     fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        if self.tied_embeddings() != source.tied_embeddings() {
+            return Err(WeightCopyError::Other("can't copy between tied and untied output heads".to_string()));
+        }
         self.transformer.copy(&source.transformer)?;
         self.head.copy(&source.head)
     }
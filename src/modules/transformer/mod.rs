@@ -1,5 +1,7 @@
 mod base;
 pub use base::*;
+mod attention;
+pub use attention::*;
 mod encoder;
 pub use encoder::*;
 mod decoder;
@@ -8,6 +10,8 @@ mod lm;
 pub use lm::*;
 mod seq2seq;
 pub use seq2seq::*;
+mod gpt2;
+pub use gpt2::*;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -1,18 +1,62 @@
-use crate::modules::{Linear, ModuleCopy, Module, WeightCopyError, PositionalEncoding};
-use tch::{nn, Tensor, Kind, IndexOp};
+use crate::modules::{BlockConfig, Linear, ModuleCopy, Module, WeightCopyError, PositionalEncoding, prefix_parameters};
+use crate::sampling::Sampler;
+use rand::Rng;
+use tch::{nn, Tensor, Kind};
 use super::{TransformerEncoder, TransformerDecoder, TransformerEncoderProps, TransformerDecoderProps};
+use serde::{Deserialize, Serialize};
 
 pub enum InferenceMode {
     NTokens(u32),
     EndToken(i64),
 }
 
+/// Settings for beam search decoding
+#[derive(Debug, Clone)]
+pub struct BeamSearchConfig {
+    pub beam_width: usize,
+    /// The maximum number of tokens to generate
+    pub max_len: i64,
+    /// Beams are finished once they generate this token
+    pub end_token: Option<i64>,
+    /// Scores are log probabilities divided by length^length_penalty, values above 0 favour longer sequences
+    pub length_penalty: f64,
+    /// Stop as soon as beam_width hypotheses are finished instead of when no live beam can beat them
+    pub early_stopping: bool,
+    /// The number of hypotheses returned for each input, at most beam_width
+    pub n_best: usize,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        BeamSearchConfig {
+            beam_width: 4,
+            max_len: 100,
+            end_token: None,
+            length_penalty: 1.,
+            early_stopping: false,
+            n_best: 1,
+        }
+    }
+}
+
+/// A finished beam search hypothesis
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// The generated tokens, not including the SOS or end tokens
+    pub tokens: Vec<i64>,
+    /// The total log probability of the generated tokens (including the end token if generated)
+    pub log_prob: f64,
+    /// The length penalized score used to rank hypotheses
+    pub score: f64,
+}
+
 /// A basic transformer encoder stack using learned embeddings
 #[derive(Debug)]
 pub struct Seq2SeqTransformer {
     encoder: TransformerEncoder,
     decoder: TransformerDecoder,
     head: Linear,
+    max_len: i64,
 }
 
 pub struct Seq2SeqTransformerProps<'a> {
@@ -26,70 +70,259 @@ pub struct Seq2SeqTransformerProps<'a> {
     pub positional_encoding: PositionalEncoding, 
     pub max_len: i64, 
     pub dropout: f64, 
+    pub block: BlockConfig,
+    /// Use the decoder's token embedding as the weight of the output head
+    pub tie_embeddings: bool,
+    /// Use one token embedding for both the encoder and decoder (they share a vocabulary)
+    pub share_embeddings: bool,
+}
+
+/// An owned, serializable version of Seq2SeqTransformerProps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Seq2SeqTransformerConfig {
+    pub n_embd: i64,
+    pub n_encoder_heads: i64,
+    pub n_decoder_heads: i64,
+    pub n_encoder_layers: i64,
+    pub n_decoder_layers: i64,
+    pub vocab_size: i64,
+    pub positional_encoding: PositionalEncoding,
+    pub max_len: i64,
+    pub dropout: f64,
+    #[serde(default = "BlockConfig::pre_norm")]
+    pub block: BlockConfig,
+    #[serde(default)]
+    pub tie_embeddings: bool,
+    #[serde(default)]
+    pub share_embeddings: bool,
+}
+
+impl Seq2SeqTransformerConfig {
+    /// Build the seq2seq transformer under the path
+    pub fn build(&self, p: &nn::Path) -> Seq2SeqTransformer {
+        Seq2SeqTransformer::new(Seq2SeqTransformerProps {
+            p,
+            n_embd: self.n_embd,
+            n_encoder_heads: self.n_encoder_heads,
+            n_decoder_heads: self.n_decoder_heads,
+            n_encoder_layers: self.n_encoder_layers,
+            n_decoder_layers: self.n_decoder_layers,
+            vocab_size: self.vocab_size,
+            positional_encoding: self.positional_encoding.clone(),
+            max_len: self.max_len,
+            dropout: self.dropout,
+            block: self.block.clone(),
+            tie_embeddings: self.tie_embeddings,
+            share_embeddings: self.share_embeddings,
+        })
+    }
 }
 
 impl Seq2SeqTransformer {
     pub fn new(props: Seq2SeqTransformerProps<'_>) -> Self {
+        let encoder = TransformerEncoder::new(TransformerEncoderProps {
+            p: &(props.p / "encoder"),
+            n_embd: props.n_embd,
+            n_head: props.n_encoder_heads,
+            vocab_size: props.vocab_size,
+            n_layers: props.n_encoder_layers,
+            positional_encoding: props.positional_encoding.clone(),
+            max_len: props.max_len,
+            dropout: props.dropout,
+            causal_mask: false,
+            block: props.block.clone(),
+        });
+        let decoder_props = TransformerDecoderProps {
+            p: &(props.p / "decoder"),
+            n_embd: props.n_embd,
+            n_head: props.n_decoder_heads,
+            n_layers: props.n_decoder_layers,
+            vocab_size: props.vocab_size,
+            positional_encoding: props.positional_encoding,
+            max_len: props.max_len,
+            dropout: props.dropout,
+            causal_mask: true,
+            block: props.block,
+        };
+        let decoder = if props.share_embeddings {
+            TransformerDecoder::with_token_embedding(decoder_props, encoder.token_embedding.shared())
+        } else {
+            TransformerDecoder::new(decoder_props)
+        };
+        let head = if props.tie_embeddings {
+            Linear::tied(&(props.p / "head"), &decoder.token_embedding.ws)
+        } else {
+            Linear::new(&(props.p / "head"), props.n_embd, props.vocab_size)
+        };
         Seq2SeqTransformer {
-            encoder: TransformerEncoder::new(TransformerEncoderProps {
-                p: &(props.p / "encoder"),
-                n_embd: props.n_embd,
-                n_head: props.n_encoder_heads,
-                vocab_size: props.vocab_size,
-                n_layers: props.n_encoder_layers,
-                positional_encoding: props.positional_encoding.clone(),
-                max_len: props.max_len,
-                dropout: props.dropout,
-                causal_mask: false,
-            }),
-            decoder: TransformerDecoder::new(TransformerDecoderProps {
-                p: &(props.p / "decoder"),
-                n_embd: props.n_embd,
-                n_head: props.n_decoder_heads,
-                n_layers: props.n_decoder_layers,
-                vocab_size: props.vocab_size,
-                positional_encoding: props.positional_encoding,
-                max_len: props.max_len,
-                dropout: props.dropout,
-                causal_mask: true
-            }),
-            head: Linear::new(&(props.p / "head"), props.n_embd, props.vocab_size),
+            encoder,
+            decoder,
+            head,
+            max_len: props.max_len,
         }
     }
 
-    pub fn forward_generate(&mut self, input: tch::Tensor, sos_index: i64, mode: InferenceMode) -> tch::Tensor {
+    /// Whether the encoder and decoder use the same token embedding
+    pub fn shared_embeddings(&self) -> bool {
+        self.encoder.token_embedding.ws.data_ptr() == self.decoder.token_embedding.ws.data_ptr()
+    }
+
+    /// Whether the output head uses the decoder's token embedding as its weight
+    pub fn tied_embeddings(&self) -> bool {
+        self.head.ws.data_ptr() == self.decoder.token_embedding.ws.data_ptr()
+    }
+
+    /// Run the full model ignoring padded positions, input_padding_mask shape: (batch size, input seq len), target_padding_mask shape: (batch size, target seq len)
+    pub fn forward_masked(&mut self, input: &Tensor, target: &Tensor, input_padding_mask: Option<&Tensor>, target_padding_mask: Option<&Tensor>) -> Tensor {
+        // Encode inputs
+        let encoded_inputs = self.encoder.forward_masked(input, input_padding_mask);
+
+        // Decode outputs, ignoring padding in both the target and the encoded inputs
+        let output_vecs = self.decoder.forward_masked(target, &encoded_inputs, target_padding_mask, input_padding_mask);
+
+        // Convert into logits over tokens
+        self.head.forward(output_vecs)
+    }
+
+    /// Generate outputs for a batch of inputs, stopping each sequence independently (call eval() first to disable dropout).
+    /// input shape: (batch size, seq len), output shape: (batch size, generated len) not including the SOS token, sequences which stopped early are filled with the pad token
+    pub fn generate<R: Rng>(&mut self, input: &Tensor, sos_index: i64, sampler: &mut Sampler<R>) -> Tensor {
+        tch::no_grad(|| {
+            let batch_size = input.size()[0];
+            // The decoder sees the SOS token and every generated token but the last
+            if let Some(max_len) = self.decoder.max_len() {
+                assert!(sampler.config.max_len <= max_len, "Tokens to generate ({}) is longer than the model's max_len ({})!", sampler.config.max_len, max_len);
+            }
+            let encoded_input = self.encoder.forward(input.shallow_clone());
+            let mut tokens = Tensor::full(&[batch_size, 1], sos_index, (Kind::Int64, input.device()));
+            let mut output = Tensor::zeros(&[batch_size, 0], (Kind::Int64, input.device()));
+            let mut finished = Tensor::zeros(&[batch_size], (Kind::Bool, input.device()));
+            for step in 0..sampler.config.max_len {
+                let logits = self.head.forward(self.decoder.forward((tokens.shallow_clone(), encoded_input.shallow_clone()))).select(1, -1);
+                // Sequences which stopped keep running on their sampled tokens (the pad token may not be a valid input), they are only padded in the output
+                let next_tokens = sampler.sample(&logits, &tokens.narrow(1, 1, step), step);
+                output = Tensor::cat(&[&output, &next_tokens.masked_fill(&finished, sampler.config.pad_token).unsqueeze(1)], 1);
+                finished = finished.logical_or(&sampler.is_stop_token(&next_tokens));
+                tokens = Tensor::cat(&[&tokens, &next_tokens.unsqueeze(1)], 1);
+                if finished.all().int64_value(&[]) == 1 {break;}
+            }
+            output
+        })
+    }
+
+    /// Decode a batch of inputs with beam search, returning the n best hypotheses for each input sorted by score.
+    /// input shape: (batch size, seq len), call eval() first to disable dropout
+    pub fn beam_search(&mut self, input: &Tensor, sos_index: i64, config: &BeamSearchConfig) -> Vec<Vec<BeamHypothesis>> {
+        assert!(config.n_best <= config.beam_width, "n_best ({}) can't be larger than beam_width ({})!", config.n_best, config.beam_width);
+        // The decoder sees the SOS token and every generated token but the last
+        if let Some(max_len) = self.decoder.max_len() {
+            assert!(config.max_len <= max_len, "Tokens to generate ({}) is longer than the model's max_len ({})!", config.max_len, max_len);
+        }
+        tch::no_grad(|| {
+            let encoded_inputs = self.encoder.forward(input.shallow_clone());
+            (0..input.size()[0])
+                .map(|i| self.beam_search_single(&encoded_inputs.narrow(0, i, 1), sos_index, config))
+                .collect()
+        })
+    }
+
+    /// Beam search for a single encoded input, shape: (1, seq len, n_embd)
+    fn beam_search_single(&mut self, encoded_input: &Tensor, sos_index: i64, config: &BeamSearchConfig) -> Vec<BeamHypothesis> {
+        let device = encoded_input.device();
+        let normalize = |log_prob: f64, len: usize| log_prob / (len.max(1) as f64).powf(config.length_penalty);
+        // Live beams of (tokens, log prob)
+        let mut beams: Vec<(Vec<i64>, f64)> = vec![(vec![], 0.)];
+        let mut finished: Vec<BeamHypothesis> = vec![];
+        for _ in 0..config.max_len {
+            // Run all live beams through the decoder together
+            let n_beams = beams.len() as i64;
+            let tokens = beams.iter()
+                .flat_map(|(tokens, _)| std::iter::once(sos_index).chain(tokens.iter().copied()))
+                .collect::<Vec<i64>>();
+            let tokens = Tensor::of_slice(&tokens).view([n_beams, -1]).to(device);
+            let log_probs = self.head.forward(
+                self.decoder.forward((tokens, encoded_input.repeat(&[n_beams, 1, 1])))
+            ).select(1, -1).log_softmax(-1, Kind::Float);
+            // log_probs: (n_beams, vocab size)
+            let vocab_size = log_probs.size()[1];
+            let beam_log_probs = Tensor::of_slice(&beams.iter().map(|(_, log_prob)| *log_prob).collect::<Vec<f64>>())
+                .to_kind(Kind::Float)
+                .to(device);
+            let scores = (log_probs + beam_log_probs.unsqueeze(1)).view([-1]);
+            // Take twice the beam width so there are enough candidates when some of them end
+            let (top_scores, top_indices) = scores.topk((2 * config.beam_width as i64).min(n_beams * vocab_size), 0, true, true);
+
+            let mut new_beams = vec![];
+            for (log_prob, index) in Vec::<f64>::from(&top_scores).into_iter().zip(Vec::<i64>::from(&top_indices)) {
+                let (beam, token) = ((index / vocab_size) as usize, index % vocab_size);
+                if Some(token) == config.end_token {
+                    let tokens = beams[beam].0.clone();
+                    finished.push(BeamHypothesis { score: normalize(log_prob, tokens.len() + 1), tokens, log_prob });
+                } else {
+                    let mut tokens = beams[beam].0.clone();
+                    tokens.push(token);
+                    new_beams.push((tokens, log_prob));
+                    if new_beams.len() == config.beam_width {break;}
+                }
+            }
+            beams = new_beams;
+
+            // Check stop conditions
+            if beams.is_empty() {break;}
+            if finished.len() >= config.beam_width {
+                finished.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+                let worst_finished = finished[config.beam_width - 1].score;
+                if config.early_stopping || normalize(beams[0].1, beams[0].0.len()) <= worst_finished {
+                    // The live beams are cut short, so they aren't hypotheses
+                    beams.clear();
+                    break;
+                }
+            }
+        }
+
+        // Beams which reached max_len without ending are hypotheses too
+        finished.extend(beams.into_iter().map(|(tokens, log_prob)| BeamHypothesis { score: normalize(log_prob, tokens.len()), tokens, log_prob }));
+        finished.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        finished.truncate(config.n_best);
+        finished
+    }
+
+    /// Autoregressively sample an output for a single input, returning the generated tokens (1, generated len) and the logits of every step (1, generated len, vocab size).
+    /// Generation stops after n tokens, once the end token has been generated (it is included in the output), or after max_len tokens
+    pub fn forward_generate(&mut self, input: tch::Tensor, sos_index: i64, mode: InferenceMode) -> (Tensor, Tensor) {
         // Input shape: (1, seq_len)
         assert_eq!(input.size()[0], 1, "During inference, can only use batch size of 1");
         // Encode input
         let encoded_input = self.encoder.forward(input);
 
-        // Iteratively decode output until stop condition based on mode
-        let mut output = Tensor::full(&[1, 1], sos_index, (Kind::Int, encoded_input.device()));
+        // Iteratively decode output until stop condition based on mode, the decoder can't see more than max_len positions
+        let max_tokens = match mode {
+            InferenceMode::NTokens(n) => (n as i64).min(self.max_len),
+            InferenceMode::EndToken(_) => self.max_len,
+        };
+        let mut tokens = Tensor::full(&[1, 1], sos_index, (Kind::Int64, encoded_input.device()));
+        let mut step_logits = Vec::new();
+        for _ in 0..max_tokens {
+            // Feed the generated prefix through the decoder and take the logits of the last position
+            let logits = self.head.forward(self.decoder.forward((tokens.shallow_clone(), encoded_input.shallow_clone()))).select(1, -1);
+            // logits: (1, vocab size)
 
-        let mut counter = 0;
-        loop {
-            // Feed through decoder
-            output = self.head.forward(self.decoder.forward((output, encoded_input.shallow_clone())));
-            // output: (1, current seq len, vocab size)
+            // Sample next token and append it to the prefix
+            let next_token = logits.softmax(-1, Kind::Float).multinomial(1, true);
+            tokens = Tensor::cat(&[&tokens, &next_token], 1);
+            step_logits.push(logits);
 
             // Exit condition
-            counter += 1;
-            match mode {
-                InferenceMode::NTokens(n) => if counter >= n {break},
-                InferenceMode::EndToken(token) => {
-                    // Argmax last token
-                    let output_token = output.i((0, 0)).argmax(Some(0), true).int64_value(&[0]);
-                    if output_token == token || counter > 5000 {break} // Provide a built in stop at 5000 tokens
-                },
+            if let InferenceMode::EndToken(token) = mode {
+                if next_token.int64_value(&[0, 0]) == token {break}
             }
-
-            // Sample outputs
-            output = output.squeeze_dim(0).softmax(-1, Kind::Float).multinomial(1, true).squeeze_dim(-1).unsqueeze(0);
-            // Cat new SOS token
-            output = Tensor::cat(&[Tensor::full(&[1, 1], sos_index, (Kind::Int, encoded_input.device())), output], 1);
         }
 
-        output
+        if step_logits.is_empty() {
+            let vocab_size = self.head.ws.size()[0];
+            return (tokens.narrow(1, 1, 0), Tensor::zeros(&[1, 0, vocab_size], (Kind::Float, encoded_input.device())));
+        }
+        (tokens.narrow(1, 1, step_logits.len() as i64), Tensor::stack(&step_logits, 1))
     }
 }
 
@@ -109,20 +342,33 @@ impl Module for Seq2SeqTransformer {
 
     fn forward(&mut self, input: Self::Input) -> Self::Output {
         let (input, target) = input;
-        // Encode inputs
-        let encoded_inputs = self.encoder.forward(input);
-
-        // Decode outputs
-        let output_vecs = self.decoder.forward((target, encoded_inputs));
+        self.forward_masked(&input, &target, None, None)
+    }
 
-        // Convert into logits over tokens
-        self.head.forward(output_vecs)
+    fn named_parameters(&self) -> Vec<(String, Tensor)> {
+        let mut parameters = prefix_parameters("encoder", self.encoder.named_parameters());
+        let mut decoder_parameters = self.decoder.named_parameters();
+        if self.shared_embeddings() {
+            // Already listed as encoder.tok_emb.weight
+            decoder_parameters.retain(|(name, _)| name != "tok_emb.weight");
+        }
+        parameters.extend(prefix_parameters("decoder", decoder_parameters));
+        let mut head_parameters = self.head.named_parameters();
+        if self.tied_embeddings() {
+            head_parameters.retain(|(name, _)| name != "weight");
+        }
+        parameters.extend(prefix_parameters("head", head_parameters));
+        parameters
     }
 }
 
 impl ModuleCopy for Seq2SeqTransformer {
     fn copy(&mut self, source: &Self) -> Result<(), WeightCopyError> {
+        if self.shared_embeddings() != source.shared_embeddings() || self.tied_embeddings() != source.tied_embeddings() {
+            return Err(WeightCopyError::Other("can't copy between models with different embedding sharing".to_string()));
+        }
         self.encoder.copy(&source.encoder)?;
-        self.decoder.copy(&source.decoder)
+        self.decoder.copy(&source.decoder)?;
+        self.head.copy(&source.head)
     }
 }
\ No newline at end of file
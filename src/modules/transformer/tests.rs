@@ -1,9 +1,48 @@
-use tch::{Device, Kind, Tensor, nn};
+use tch::{Device, IndexOp, Kind, Tensor, nn};
 
-use crate::{modules::{Module, Seq2SeqTransformer, Seq2SeqTransformerProps}, utils::count_parameters};
+use crate::{
+    checkpoint::{self, CheckpointMetadata},
+    sampling::{Sampler, SamplingConfig},
+    modules::{
+        Activation, BlockConfig, NormKind, NormPosition, PositionalEncoding, InferenceMode, StateDict, Module, ModuleCopy,
+        MultiHeadAttention, MultiHeadAttentionProps, TransformerBlock, TransformerDecoderBlock, TransformerCache,
+        LanguageModel, LanguageModelConfig, LanguageModelProps, Seq2SeqTransformer, Seq2SeqTransformerProps, BeamSearchConfig,
+        GPT2Config, load_gpt2, read_state_dict, save_state_dict,
+    },
+    utils::{count_parameters, count_trainable_parameters, count_frozen_parameters, count_module_parameters},
+};
 
 use super::super::{TransformerAggregator, TransformerAggregatorProps, TransformerEncoder, TransformerEncoderProps};
 
+/// A small language model for tests: 64 dims, 4 heads, 2 layers, 120 tokens, rotary embeddings and the default block
+fn small_lm_props<'a>(p: &'a nn::Path<'a>) -> LanguageModelProps<'a> {
+    LanguageModelProps {
+        p,
+        n_embd: 64,
+        n_head: 4,
+        n_layers: 2,
+        vocab_size: 120,
+        positional_encoding: PositionalEncoding::Rotary,
+        max_len: 8,
+        dropout: 0.1,
+        block: Default::default(),
+        tie_embeddings: false,
+    }
+}
+
+/// Number of parameters in an untied language model with the default block (2x MLP, LayerNorm, as many key/value heads as query heads)
+fn lm_parameter_count(props: &LanguageModelProps) -> u64 {
+    let (n_embd, vocab_size) = (props.n_embd as u64, props.vocab_size as u64);
+    let linear = |in_dim: u64, out_dim: u64| in_dim * out_dim + out_dim;
+    let norm = 2 * n_embd;
+    let block = 4 * linear(n_embd, n_embd) + linear(n_embd, 2 * n_embd) + linear(2 * n_embd, n_embd) + 2 * norm;
+    let position_embedding = match props.positional_encoding {
+        PositionalEncoding::Learned => props.max_len as u64 * n_embd,
+        _ => 0,
+    };
+    vocab_size * n_embd + position_embedding + props.n_layers as u64 * block + norm + linear(n_embd, vocab_size)
+}
+
 #[test]
 fn test_transformer_encoder() {
     let vs = nn::VarStore::new(Device::cuda_if_available());
@@ -18,6 +57,7 @@ fn test_transformer_encoder() {
             max_len: 110,
             dropout: 0.1,
             causal_mask: true,
+            block: Default::default(),
         });
     let input = Tensor::randint(119, &[15, 50], (Kind::Int, Device::cuda_if_available()));
     let output = transformer_encoder.forward(input);
@@ -38,6 +78,7 @@ fn test_transformer_aggregator() {
         positional_encoding: crate::modules::PositionalEncoding::Learned,
         max_len: 110,
         dropout: 0.1,
+        block: Default::default(),
     });
     let input = Tensor::randint(119, &[15, 50], (Kind::Int, Device::cuda_if_available()));
     let output = transformer_aggregator.forward(input);
@@ -58,6 +99,7 @@ fn test_transformer_aggregator_from_encoder() {
         max_len: 110,
         dropout: 0.1,
         causal_mask: true,
+        block: Default::default(),
     });
     let mut transformer_aggregator = TransformerAggregator::from_encoder(&(&vs.root() / "transformer"), transformer_encoder, 150);
     let input = Tensor::randint(119, &[15, 50], (Kind::Int, Device::cuda_if_available()));
@@ -66,6 +108,42 @@ fn test_transformer_aggregator_from_encoder() {
     assert_eq!(count_parameters(&vs), 281_750);
 }
 
+#[test]
+fn test_freeze() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let transformer_encoder = TransformerEncoder::new(TransformerEncoderProps {
+        p: &(&vs.root() / "transformer"),
+        n_embd: 100,
+        n_head: 10,
+        n_layers: 3,
+        vocab_size: 120,
+        positional_encoding: crate::modules::PositionalEncoding::Learned,
+        max_len: 110,
+        dropout: 0.1,
+        causal_mask: true,
+        block: Default::default(),
+    });
+    let mut transformer_aggregator = TransformerAggregator::from_encoder(&(&vs.root() / "transformer"), transformer_encoder, 150);
+    // Freezing the encoder leaves only the aggregation vector and head trainable
+    transformer_aggregator.encoder.freeze();
+    assert_eq!(count_trainable_parameters(&vs), 15_250);
+    assert_eq!(count_frozen_parameters(&vs), 266_500);
+    assert_eq!(count_module_parameters(&transformer_aggregator), (15_250, 266_500));
+    assert_eq!(count_module_parameters(&transformer_aggregator.head), (15_150, 0));
+
+    // Frozen parameters don't receive gradients
+    let input = Tensor::randint(119, &[4, 20], (Kind::Int64, Device::cuda_if_available()));
+    transformer_aggregator.forward(input).sum(Kind::Float).backward();
+    assert!(transformer_aggregator.encoder.parameters().iter().all(|t| !t.grad().defined()));
+    assert!(transformer_aggregator.head.parameters().iter().all(|t| t.grad().defined()));
+
+    // Freeze only the first 2 blocks (81_100 parameters each)
+    transformer_aggregator.encoder.unfreeze();
+    transformer_aggregator.encoder.freeze_blocks(2);
+    assert_eq!(count_frozen_parameters(&vs), 162_200);
+    assert_eq!(count_parameters(&vs), 281_750);
+}
+
 #[test]
 fn test_transformer_seq2seq() {
     let vs = nn::VarStore::new(Device::cuda_if_available());
@@ -81,6 +159,9 @@ fn test_transformer_seq2seq() {
             positional_encoding: crate::modules::PositionalEncoding::Learned,
             max_len: 110,
             dropout: 0.1,
+            block: Default::default(),
+            tie_embeddings: false,
+            share_embeddings: false,
         });
 
     // batch size : 15, seq len: 50
@@ -88,4 +169,738 @@ fn test_transformer_seq2seq() {
     let output = transformer_seq2seq.forward((input.shallow_clone(), input));
     assert_eq!(output.size(), &[15, 50, 120]);
     assert_eq!(count_parameters(&vs), 666920);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_language_model_cached_step() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut language_model = LanguageModel::new(LanguageModelProps {
+        positional_encoding: PositionalEncoding::Sinusoidal,
+        max_len: 32,
+        ..small_lm_props(&(&vs.root() / "lm"))
+    });
+    language_model.eval();
+    let input = Tensor::randint(119, &[3, 12], (Kind::Int64, Device::cuda_if_available()));
+    let full_output = language_model.forward(input.shallow_clone());
+
+    // Prefill the first 5 tokens, then feed one token at a time
+    let mut cache = TransformerCache::new();
+    let mut outputs = vec![language_model.forward_step(&input.i((.., ..5)), &mut cache)];
+    for i in 5..12 {
+        outputs.push(language_model.forward_step(&input.i((.., i..i + 1)), &mut cache));
+    }
+    assert_eq!(cache.len(), 12);
+    let cached_output = Tensor::cat(&outputs, 1);
+    assert_eq!(cached_output.size(), &[3, 12, 120]);
+    assert!(cached_output.allclose(&full_output, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn test_padding_mask() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut transformer_aggregator = TransformerAggregator::new(TransformerAggregatorProps {
+        p: &(&vs.root() / "transformer"),
+        n_embd: 64,
+        n_head: 4,
+        n_layers: 2,
+        aggregation_size: 10,
+        vocab_size: 120,
+        positional_encoding: crate::modules::PositionalEncoding::Learned,
+        max_len: 32,
+        dropout: 0.1,
+        block: Default::default(),
+    });
+    transformer_aggregator.eval();
+    // Last 4 positions of every sequence are padding
+    let input = Tensor::randint(119, &[3, 12], (Kind::Int64, Device::cuda_if_available()));
+    let padding_mask = Tensor::cat(&[
+        Tensor::zeros(&[3, 8], (Kind::Bool, Device::cuda_if_available())),
+        Tensor::ones(&[3, 4], (Kind::Bool, Device::cuda_if_available())),
+    ], 1);
+    let other_padding = Tensor::cat(&[input.i((.., ..8)), Tensor::zeros(&[3, 4], (Kind::Int64, Device::cuda_if_available()))], 1);
+
+    // Changing padded tokens doesn't change the aggregated output
+    let output = transformer_aggregator.forward_masked(&input, Some(&padding_mask));
+    let other_output = transformer_aggregator.forward_masked(&other_padding, Some(&padding_mask));
+    assert_eq!(output.size(), &[3, 10]);
+    assert!(output.allclose(&other_output, 1e-5, 1e-5, false));
+
+    // Masked positions are ignored in the same way as if they weren't there
+    let truncated_output = transformer_aggregator.forward(input.i((.., ..8)));
+    assert!(output.allclose(&truncated_output, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn test_rotary_language_model() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let p = &vs.root() / "lm";
+    let props = small_lm_props(&p);
+    let n_parameters = lm_parameter_count(&props);
+    let mut language_model = LanguageModel::new(props);
+    language_model.eval();
+    // Rotary embeddings aren't limited by max_len and add no parameters
+    let input = Tensor::randint(119, &[3, 20], (Kind::Int64, Device::cuda_if_available()));
+    let full_output = language_model.forward(input.shallow_clone());
+    assert_eq!(full_output.size(), &[3, 20, 120]);
+    assert_eq!(count_parameters(&vs), n_parameters);
+
+    // Cached decoding uses the correct position offsets
+    let mut cache = TransformerCache::new();
+    let mut outputs = vec![language_model.forward_step(&input.i((.., ..5)), &mut cache)];
+    for i in 5..20 {
+        outputs.push(language_model.forward_step(&input.i((.., i..i + 1)), &mut cache));
+    }
+    assert!(Tensor::cat(&outputs, 1).allclose(&full_output, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn test_alibi_language_model() {
+    assert_eq!(super::alibi_slopes(8), vec![0.5, 0.25, 0.125, 0.0625, 0.03125, 0.015625, 0.0078125, 0.00390625]);
+    assert_eq!(super::alibi_slopes(6).len(), 6);
+
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let p = &vs.root() / "lm";
+    let props = LanguageModelProps { positional_encoding: PositionalEncoding::ALiBi, ..small_lm_props(&p) };
+    let n_parameters = lm_parameter_count(&props);
+    let mut language_model = LanguageModel::new(props);
+    language_model.eval();
+    // ALiBi isn't limited by max_len and adds no parameters
+    let input = Tensor::randint(119, &[3, 20], (Kind::Int64, Device::cuda_if_available()));
+    let full_output = language_model.forward(input.shallow_clone());
+    assert_eq!(full_output.size(), &[3, 20, 120]);
+    assert_eq!(count_parameters(&vs), n_parameters);
+
+    let mut cache = TransformerCache::new();
+    let mut outputs = vec![language_model.forward_step(&input.i((.., ..5)), &mut cache)];
+    for i in 5..20 {
+        outputs.push(language_model.forward_step(&input.i((.., i..i + 1)), &mut cache));
+    }
+    assert!(Tensor::cat(&outputs, 1).allclose(&full_output, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn test_language_model_generate() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut language_model = LanguageModel::new(LanguageModelProps {
+        positional_encoding: PositionalEncoding::Learned,
+        max_len: 32,
+        ..small_lm_props(&(&vs.root() / "lm"))
+    });
+    language_model.eval();
+    let prompt = Tensor::randint(119, &[3, 5], (Kind::Int64, Device::cuda_if_available()));
+
+    // Seeded samplers give reproducible outputs
+    let config = SamplingConfig { temperature: 0.8, top_k: Some(20), top_p: Some(0.9), repetition_penalty: 1.2, max_len: 10, ..Default::default() };
+    let output = language_model.generate(&prompt, &mut Sampler::seeded(config.clone(), 7));
+    assert_eq!(output.size(), &[3, 10]);
+    assert_eq!(output, language_model.generate(&prompt, &mut Sampler::seeded(config, 7)));
+
+    // Every token but 119 is a stop token, so only 119 can be generated before min_len
+    let config = SamplingConfig { min_len: 3, stop_tokens: (0..119).collect(), pad_token: -1, max_len: 10, ..Default::default() };
+    let output = Vec::<Vec<i64>>::from(&language_model.generate(&prompt, &mut Sampler::seeded(config, 7)));
+    for sequence in output {
+        assert_eq!(&sequence[..3], &[119, 119, 119]);
+        // Sequences which stop are padded until every sequence has stopped
+        if let Some(stop) = sequence.iter().position(|t| (0..119).contains(t)) {
+            assert!(sequence[stop + 1..].iter().all(|t| *t == -1));
+        }
+    }
+}
+
+#[test]
+fn test_seq2seq_generate() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut transformer_seq2seq = Seq2SeqTransformer::new(
+        Seq2SeqTransformerProps {
+            p: &(&vs.root() / "transformer"),
+            n_embd: 64,
+            n_encoder_heads: 4,
+            n_encoder_layers: 2,
+            n_decoder_heads: 4,
+            n_decoder_layers: 2,
+            vocab_size: 120,
+            positional_encoding: crate::modules::PositionalEncoding::Sinusoidal,
+            max_len: 32,
+            dropout: 0.1,
+            block: Default::default(),
+            tie_embeddings: false,
+            share_embeddings: false,
+        });
+    transformer_seq2seq.eval();
+    let input = Tensor::randint(119, &[4, 12], (Kind::Int64, Device::cuda_if_available()));
+
+    let config = SamplingConfig { greedy: true, max_len: 8, ..Default::default() };
+    let output = transformer_seq2seq.generate(&input, 0, &mut Sampler::seeded(config, 0));
+    assert_eq!(output.size(), &[4, 8]);
+
+    // Sequences which stop are padded until every sequence has stopped
+    let config = SamplingConfig { stop_tokens: (0..60).collect(), pad_token: -1, max_len: 8, ..Default::default() };
+    let output = Vec::<Vec<i64>>::from(&transformer_seq2seq.generate(&input, 0, &mut Sampler::seeded(config, 0)));
+    for sequence in output {
+        if let Some(stop) = sequence.iter().position(|t| (0..60).contains(t)) {
+            assert!(sequence[stop + 1..].iter().all(|t| *t == -1));
+        }
+    }
+}
+
+#[test]
+fn test_seq2seq_beam_search() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut transformer_seq2seq = Seq2SeqTransformer::new(
+        Seq2SeqTransformerProps {
+            p: &(&vs.root() / "transformer"),
+            n_embd: 64,
+            n_encoder_heads: 4,
+            n_encoder_layers: 2,
+            n_decoder_heads: 4,
+            n_decoder_layers: 2,
+            vocab_size: 120,
+            positional_encoding: crate::modules::PositionalEncoding::Sinusoidal,
+            max_len: 32,
+            dropout: 0.1,
+            block: Default::default(),
+            tie_embeddings: false,
+            share_embeddings: false,
+        });
+    transformer_seq2seq.eval();
+    let input = Tensor::randint(119, &[2, 12], (Kind::Int64, Device::cuda_if_available()));
+
+    // A beam width of 1 is greedy decoding
+    let greedy = transformer_seq2seq.generate(&input, 0, &mut Sampler::new(SamplingConfig { greedy: true, max_len: 6, ..Default::default() }));
+    let beams = transformer_seq2seq.beam_search(&input, 0, &BeamSearchConfig { beam_width: 1, max_len: 6, ..Default::default() });
+    assert_eq!(beams.len(), 2);
+    assert_eq!(Vec::<Vec<i64>>::from(&greedy), beams.iter().map(|b| b[0].tokens.clone()).collect::<Vec<_>>());
+
+    // N-best lists are sorted and their log probabilities match a teacher forced pass
+    let config = BeamSearchConfig { beam_width: 3, max_len: 6, n_best: 3, end_token: Some(5), ..Default::default() };
+    let beams = transformer_seq2seq.beam_search(&input, 0, &config);
+    for (i, hypotheses) in beams.iter().enumerate() {
+        assert_eq!(hypotheses.len(), 3);
+        assert!(hypotheses.windows(2).all(|w| w[0].score >= w[1].score));
+        let best = &hypotheses[0];
+        let mut target = vec![0];
+        target.extend(&best.tokens);
+        let mut expected = best.tokens.clone();
+        if best.tokens.len() < 6 {
+            expected.push(5);
+        } else {
+            target.pop();
+        }
+        let target = Tensor::of_slice(&target).unsqueeze(0).to(Device::cuda_if_available());
+        let expected = Tensor::of_slice(&expected).unsqueeze(0).unsqueeze(-1).to(Device::cuda_if_available());
+        let log_probs = tch::no_grad(|| transformer_seq2seq.forward((input.narrow(0, i as i64, 1), target)))
+            .log_softmax(-1, Kind::Float)
+            .gather(2, &expected, false);
+        assert!((log_probs.sum(Kind::Double).double_value(&[]) - best.log_prob).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn test_seq2seq_forward_generate() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let mut transformer_seq2seq = Seq2SeqTransformer::new(
+        Seq2SeqTransformerProps {
+            p: &(&vs.root() / "transformer"),
+            n_embd: 32,
+            n_encoder_heads: 4,
+            n_encoder_layers: 1,
+            n_decoder_heads: 4,
+            n_decoder_layers: 1,
+            vocab_size: 20,
+            positional_encoding: crate::modules::PositionalEncoding::Learned,
+            max_len: 10,
+            dropout: 0.1,
+            block: Default::default(),
+            tie_embeddings: false,
+            share_embeddings: false,
+        });
+    transformer_seq2seq.eval();
+    // Make the model deterministic: the head always puts all probability on token 7
+    tch::no_grad(|| {
+        let variables = vs.variables();
+        let _ = variables["transformer.head.weight"].shallow_clone().zero_();
+        let _ = variables["transformer.head.bias"].shallow_clone().fill_(-1e4);
+        let _ = variables["transformer.head.bias"].i(7).fill_(0.);
+    });
+    let input = Tensor::randint(19, &[1, 8], (Kind::Int64, Device::cuda_if_available()));
+
+    let (tokens, logits) = transformer_seq2seq.forward_generate(input.shallow_clone(), 1, InferenceMode::NTokens(4));
+    assert_eq!(Vec::<i64>::from(&tokens), vec![7, 7, 7, 7]);
+    assert_eq!(logits.size(), &[1, 4, 20]);
+
+    // Stops as soon as the end token is generated
+    let (tokens, logits) = transformer_seq2seq.forward_generate(input.shallow_clone(), 1, InferenceMode::EndToken(7));
+    assert_eq!(Vec::<i64>::from(&tokens), vec![7]);
+    assert_eq!(logits.size(), &[1, 1, 20]);
+
+    // Never generating the end token stops at max_len
+    let (tokens, logits) = transformer_seq2seq.forward_generate(input.shallow_clone(), 1, InferenceMode::EndToken(3));
+    assert_eq!(tokens.size(), &[1, 10]);
+    assert_eq!(logits.size(), &[1, 10, 20]);
+    let (tokens, _) = transformer_seq2seq.forward_generate(input, 1, InferenceMode::NTokens(50));
+    assert_eq!(tokens.size(), &[1, 10]);
+}
+
+#[test]
+fn test_named_parameters() {
+    let vs = nn::VarStore::new(Device::cuda_if_available());
+    let p = &vs.root() / "lm";
+    let props = LanguageModelProps { positional_encoding: PositionalEncoding::Learned, ..small_lm_props(&p) };
+    let n_parameters = lm_parameter_count(&props);
+    let language_model = LanguageModel::new(props);
+    let count = |parameters: Vec<Tensor>| parameters.iter().map(|t| t.numel() as u64).sum::<u64>();
+    // The module enumerates every parameter in the VarStore, with the same dotted names
+    let named = language_model.named_parameters();
+    assert_eq!(count(language_model.parameters()), count_parameters(&vs));
+    assert_eq!(count_parameters(&vs), n_parameters);
+    let variables = vs.variables();
+    for (name, tensor) in &named {
+        assert_eq!(variables[&format!("lm.{}", name)].size(), tensor.size());
+    }
+    assert!(named.iter().any(|(name, _)| name == "transformer.1.attn.key.weight"));
+    assert!(named.iter().any(|(name, _)| name == "transformer.pos_emb"));
+    // Submodules can be counted on their own
+    assert_eq!(count(language_model.head.parameters()), 64 * 120 + 120);
+}
+
+#[test]
+fn test_state_dict() {
+    fn props<'a>(p: &'a nn::Path<'a>) -> LanguageModelProps<'a> {
+        LanguageModelProps { positional_encoding: PositionalEncoding::Learned, ..small_lm_props(p) }
+    }
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut source = LanguageModel::new(props(&(&vs.root() / "source")));
+    // The state dict doesn't depend on where the model lives in the VarStore
+    let other_vs = nn::VarStore::new(Device::Cpu);
+    let mut target = LanguageModel::new(props(&(&other_vs.root() / "somewhere" / "else")));
+    let path = std::env::temp_dir().join("condor_test_state_dict.ot");
+    save_state_dict(&source.state_dict(), &path).unwrap();
+    let state_dict = read_state_dict(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(target.load_state_dict(&state_dict, true).unwrap().is_empty());
+
+    source.eval();
+    target.eval();
+    let input = Tensor::randint(119, &[2, 8], (Kind::Int64, Device::Cpu));
+    assert!(source.forward(input.shallow_clone()).allclose(&target.forward(input), 1e-5, 1e-5, false));
+}
+
+#[test]
+fn test_config_checkpoint() {
+    let config = LanguageModelConfig {
+        n_embd: 64,
+        n_head: 4,
+        n_layers: 2,
+        vocab_size: 120,
+        positional_encoding: crate::modules::PositionalEncoding::Rotary,
+        max_len: 8,
+        dropout: 0.1,
+        block: BlockConfig::pre_norm(),
+        tie_embeddings: false,
+    };
+    let json = serde_json::to_string(&config).unwrap();
+    assert!(json.contains("\"positional_encoding\":\"Rotary\""));
+    assert_eq!(serde_json::from_str::<LanguageModelConfig>(&json).unwrap(), config);
+    // Configs without block settings use pre-norm blocks rather than the legacy layout
+    let minimal = r#"{"n_embd": 64, "n_head": 4, "n_layers": 2, "vocab_size": 120, "positional_encoding": "Rotary", "max_len": 8, "dropout": 0.1}"#;
+    assert_eq!(serde_json::from_str::<LanguageModelConfig>(minimal).unwrap(), config);
+    let empty_block = r#"{"n_embd": 64, "n_head": 4, "n_layers": 2, "vocab_size": 120, "positional_encoding": "Rotary", "max_len": 8, "dropout": 0.1, "block": {}}"#;
+    assert_eq!(serde_json::from_str::<LanguageModelConfig>(empty_block).unwrap().block.norm_position, NormPosition::Pre);
+
+    // Save the config and weights, then rebuild the model from disk
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut language_model = config.build(&(&vs.root() / "lm"));
+    let dir = std::env::temp_dir().join("condor_test_config_checkpoint");
+    std::fs::create_dir_all(&dir).unwrap();
+    checkpoint::save_config(&config, dir.join("config.json")).unwrap();
+    checkpoint::save_module(&language_model, &CheckpointMetadata::with_config(&config).unwrap(), dir.join("model.safetensors")).unwrap();
+
+    let loaded_config: LanguageModelConfig = checkpoint::read_config(dir.join("config.json")).unwrap();
+    let loaded_vs = nn::VarStore::new(Device::Cpu);
+    let mut loaded_model = loaded_config.build(&loaded_vs.root());
+    let (_, metadata) = checkpoint::load_module(&mut loaded_model, dir.join("model.safetensors"), true).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(metadata.parse_config::<LanguageModelConfig>().unwrap(), Some(config));
+
+    language_model.eval();
+    loaded_model.eval();
+    let input = Tensor::randint(119, &[2, 8], (Kind::Int64, Device::Cpu));
+    assert!(language_model.forward(input.shallow_clone()).allclose(&loaded_model.forward(input), 1e-5, 1e-5, false));
+}
+
+/// A direct implementation of the GPT-2 forward pass using Hugging Face weight names
+fn gpt2_reference(weights: &StateDict, config: &GPT2Config, input: &Tensor) -> Tensor {
+    let w = |name: &str| &weights[&format!("transformer.{}", name)];
+    let layer_norm = |x: &Tensor, name: &str| Tensor::layer_norm(x, &[config.n_embd], Some(w(&format!("{}.weight", name))), Some(w(&format!("{}.bias", name))), 1e-5, false);
+    let conv1d = |x: &Tensor, name: &str| x.matmul(w(&format!("{}.weight", name))) + w(&format!("{}.bias", name));
+    let (sz_b, sz_t) = input.size2().unwrap();
+    let head_size = config.n_embd / config.n_head;
+    let mask = Tensor::ones(&[sz_t, sz_t], (Kind::Float, Device::Cpu)).tril(0);
+    let mut x = Tensor::embedding(w("wte.weight"), input, -1, false, false) + w("wpe.weight").narrow(0, 0, sz_t);
+    for layer in 0..config.n_layer {
+        let h = layer_norm(&x, &format!("h.{}.ln_1", layer));
+        let qkv = conv1d(&h, &format!("h.{}.attn.c_attn", layer)).split(config.n_embd, -1);
+        let heads: Vec<Tensor> = qkv.iter().map(|t| t.view([sz_b, sz_t, config.n_head, head_size]).transpose(1, 2)).collect();
+        let att = (heads[0].matmul(&heads[1].transpose(-2, -1)) / (head_size as f64).sqrt())
+            .masked_fill(&mask.eq(0.), f64::NEG_INFINITY)
+            .softmax(-1, Kind::Float);
+        let y = att.matmul(&heads[2]).transpose(1, 2).contiguous().view([sz_b, sz_t, config.n_embd]);
+        x = x + conv1d(&y, &format!("h.{}.attn.c_proj", layer));
+        let m = conv1d(&layer_norm(&x, &format!("h.{}.ln_2", layer)), &format!("h.{}.mlp.c_fc", layer));
+        // GPT-2 uses the tanh approximation of GeLU
+        let m = &m * 0.5 * (((&m + &m * &m * &m * 0.044715) * (2. / std::f64::consts::PI).sqrt()).tanh() + 1.);
+        x = x + conv1d(&m, &format!("h.{}.mlp.c_proj", layer));
+    }
+    layer_norm(&x, "ln_f").matmul(&w("wte.weight").tr())
+}
+
+#[test]
+fn test_load_gpt2() {
+    let config = GPT2Config {
+        n_embd: 16,
+        n_head: 4,
+        n_layer: 2,
+        vocab_size: 50,
+        n_positions: 12,
+    };
+    // Generate a tiny GPT-2 checkpoint with random weights, in the Hugging Face GPT2LMHeadModel layout
+    let randn = |size: &[i64]| Tensor::randn(size, (Kind::Float, Device::Cpu)) * 0.1;
+    let mut weights = StateDict::new();
+    let mut add = |name: String, tensor: Tensor| weights.insert(format!("transformer.{}", name), tensor);
+    add("wte.weight".to_string(), randn(&[config.vocab_size, config.n_embd]));
+    add("wpe.weight".to_string(), randn(&[config.n_positions, config.n_embd]));
+    add("ln_f.weight".to_string(), randn(&[config.n_embd]) + 1.);
+    add("ln_f.bias".to_string(), randn(&[config.n_embd]));
+    for layer in 0..config.n_layer {
+        for (name, size_in, size_out) in [("attn.c_attn", 16, 48), ("attn.c_proj", 16, 16), ("mlp.c_fc", 16, 64), ("mlp.c_proj", 64, 16)] {
+            add(format!("h.{}.{}.weight", layer, name), randn(&[size_in, size_out]));
+            add(format!("h.{}.{}.bias", layer, name), randn(&[size_out]));
+        }
+        for name in ["ln_1", "ln_2"] {
+            add(format!("h.{}.{}.weight", layer, name), randn(&[config.n_embd]) + 1.);
+            add(format!("h.{}.{}.bias", layer, name), randn(&[config.n_embd]));
+        }
+        // Buffers which aren't weights
+        add(format!("h.{}.attn.bias", layer), Tensor::ones(&[1, 1, 12, 12], (Kind::Float, Device::Cpu)).tril(0));
+    }
+    weights.insert("lm_head.weight".to_string(), weights["transformer.wte.weight"].shallow_clone());
+    let path = std::env::temp_dir().join("condor_test_gpt2.safetensors");
+    checkpoint::save(&weights, &CheckpointMetadata::default(), &path).unwrap();
+
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut model = load_gpt2(&vs.root(), &config, &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // 4x feed forward layers and the same number of parameters as GPT-2 (plus the head bias)
+    assert!(model.tied_embeddings());
+    assert_eq!(count_parameters(&vs), 2 * (2 * 32 + 16 * 48 + 48 + 16 * 16 + 16 + 16 * 64 + 64 + 64 * 16 + 16) + 50 * 16 + 12 * 16 + 32 + 50);
+
+    model.eval();
+    let input = Tensor::randint(50, &[2, 12], (Kind::Int64, Device::Cpu));
+    let output = model.forward(input.shallow_clone());
+    let expected = tch::no_grad(|| gpt2_reference(&weights, &config, &input));
+    assert!(output.allclose(&expected, 1e-5, 1e-5, false));
+}
+
+#[test]
+fn test_feed_forward_config() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let p = &vs.root() / "lm";
+    let props = LanguageModelProps {
+        block: BlockConfig {
+            ffn_dim: Some(96),
+            activation: Activation::SiLU,
+            gated: true,
+            ..Default::default()
+        },
+        ..small_lm_props(&p)
+    };
+    let default_parameters = lm_parameter_count(&props);
+    let mut language_model = LanguageModel::new(props);
+    let output = language_model.forward(Tensor::randint(119, &[3, 8], (Kind::Int64, Device::Cpu)));
+    assert_eq!(output.size(), &[3, 8, 120]);
+    // SwiGLU doubles the first feed forward layer: (64 * 192 + 192) + (96 * 64 + 64) per block instead of the default 2x MLP
+    let default_ffn = (64 * 128 + 128) + (128 * 64 + 64);
+    assert_eq!(count_parameters(&vs), default_parameters + 2 * ((64 * 192 + 192) + (96 * 64 + 64)) - 2 * default_ffn);
+    let lin1 = language_model.named_parameters().into_iter().find(|(name, _)| name == "transformer.0.lin1.weight").unwrap().1;
+    assert_eq!(lin1.size(), &[192, 64]);
+}
+
+#[test]
+fn test_norm_position() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let post_norm = BlockConfig { norm_position: NormPosition::Post, ..Default::default() };
+    let pre_norm = BlockConfig { norm_position: NormPosition::Pre, ..Default::default() };
+    let mut pre_block = TransformerBlock::new(&(&vs.root() / "pre"), 32, 4, 0., true, PositionalEncoding::Learned, pre_norm);
+    let mut post_block = TransformerBlock::new(&(&vs.root() / "post"), 32, 4, 0., true, PositionalEncoding::Learned, post_norm.clone());
+    let mut post_decoder_block = TransformerDecoderBlock::new(&(&vs.root() / "post_decoder"), 32, 4, 0., true, PositionalEncoding::Learned, post_norm);
+    let input = Tensor::randn(&[2, 6, 32], (Kind::Float, Device::Cpu)) * 3. + 1.;
+    let encoder_output = Tensor::randn(&[2, 4, 32], (Kind::Float, Device::Cpu));
+
+    // Post-norm blocks end with a layer norm, so every position is normalized
+    for output in [post_block.forward(input.shallow_clone()), post_decoder_block.forward((input.shallow_clone(), encoder_output))] {
+        assert!(output.mean_dim(&[-1], false, Kind::Float).abs().max().double_value(&[]) < 1e-4);
+        assert!((output.std_dim(&[-1], false, false) - 1.).abs().max().double_value(&[]) < 1e-3);
+    }
+    // The norm epsilon reaches the layers, a huge epsilon shrinks the normalized outputs
+    let large_eps = BlockConfig { norm_position: NormPosition::Post, norm_eps: Some(1e4), ..Default::default() };
+    let mut large_eps_block = TransformerBlock::new(&(&vs.root() / "large_eps"), 32, 4, 0., true, PositionalEncoding::Learned, large_eps);
+    assert!(large_eps_block.forward(input.shallow_clone()).std_dim(&[-1], false, false).max().double_value(&[]) < 0.5);
+    // Pre-norm blocks keep the residual stream unnormalized
+    let output = pre_block.forward(input.shallow_clone());
+    assert!(output.mean_dim(&[-1], false, Kind::Float).abs().max().double_value(&[]) > 0.1);
+
+    // The default is still the legacy layout, which gives different outputs from pre-norm with the same weights
+    assert_eq!(BlockConfig::default().norm_position, NormPosition::Legacy);
+    let mut legacy_block = TransformerBlock::new(&(&vs.root() / "legacy"), 32, 4, 0., true, PositionalEncoding::Learned, BlockConfig::default());
+    legacy_block.copy(&pre_block).unwrap();
+    assert!(!legacy_block.forward(input.shallow_clone()).allclose(&output, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn test_rms_norm_language_model() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let p = &vs.root() / "lm";
+    let props = LanguageModelProps {
+        block: BlockConfig {
+            norm: NormKind::RMSNorm,
+            ..Default::default()
+        },
+        ..small_lm_props(&p)
+    };
+    let layer_norm_parameters = lm_parameter_count(&props);
+    let mut language_model = LanguageModel::new(props);
+    let output = language_model.forward(Tensor::randint(119, &[3, 8], (Kind::Int64, Device::Cpu)));
+    assert_eq!(output.size(), &[3, 8, 120]);
+    // RMSNorm has no bias, which removes 64 parameters from each of the 5 norms
+    assert_eq!(count_parameters(&vs), layer_norm_parameters - 5 * 64);
+}
+
+#[test]
+fn test_tied_embeddings() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    fn props<'a>(p: &'a nn::Path<'a>, tie_embeddings: bool) -> LanguageModelProps<'a> {
+        LanguageModelProps { tie_embeddings, ..small_lm_props(p) }
+    }
+    let p = &vs.root() / "lm";
+    let untied_parameters = lm_parameter_count(&props(&p, false));
+    let mut language_model = LanguageModel::new(props(&p, true));
+    assert!(language_model.tied_embeddings());
+    // The head only adds its bias
+    assert_eq!(count_parameters(&vs), untied_parameters - 120 * 64);
+    assert_eq!(language_model.named_parameters().iter().map(|(_, t)| t.numel() as u64).sum::<u64>(), count_parameters(&vs));
+    let input = Tensor::randint(119, &[3, 8], (Kind::Int64, Device::Cpu));
+    assert_eq!(language_model.forward(input).size(), &[3, 8, 120]);
+
+    // Copies keep the head tied, but can't mix tied and untied models
+    let other_vs = nn::VarStore::new(Device::Cpu);
+    let mut tied = LanguageModel::new(props(&(&other_vs.root() / "tied"), true));
+    let mut untied = LanguageModel::new(props(&(&other_vs.root() / "untied"), false));
+    tied.copy(&language_model).unwrap();
+    assert!(tied.tied_embeddings());
+    assert!(tied.head.ws.equal(&language_model.head.ws));
+    assert!(untied.copy(&language_model).is_err());
+
+    // A seq2seq model with one embedding for the encoder, decoder and head
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut transformer_seq2seq = Seq2SeqTransformer::new(
+        Seq2SeqTransformerProps {
+            p: &(&vs.root() / "transformer"),
+            n_embd: 64,
+            n_encoder_heads: 4,
+            n_encoder_layers: 1,
+            n_decoder_heads: 4,
+            n_decoder_layers: 1,
+            vocab_size: 120,
+            positional_encoding: crate::modules::PositionalEncoding::Rotary,
+            max_len: 8,
+            dropout: 0.1,
+            block: Default::default(),
+            tie_embeddings: true,
+            share_embeddings: true,
+        });
+    assert!(transformer_seq2seq.shared_embeddings() && transformer_seq2seq.tied_embeddings());
+    let names: Vec<String> = transformer_seq2seq.named_parameters().into_iter().map(|(name, _)| name).collect();
+    assert!(names.contains(&"encoder.tok_emb.weight".to_string()));
+    assert!(!names.contains(&"decoder.tok_emb.weight".to_string()));
+    assert!(!names.contains(&"head.weight".to_string()));
+    assert_eq!(transformer_seq2seq.named_parameters().iter().map(|(_, t)| t.numel() as u64).sum::<u64>(), count_parameters(&vs));
+    let input = Tensor::randint(119, &[3, 8], (Kind::Int64, Device::Cpu));
+    assert_eq!(transformer_seq2seq.forward((input.shallow_clone(), input)).size(), &[3, 8, 120]);
+}
+
+#[test]
+fn test_grouped_query_attention() {
+    for n_kv_heads in [2, 1] {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let p = &vs.root() / "lm";
+        let props = LanguageModelProps {
+            block: BlockConfig {
+                n_kv_heads: Some(n_kv_heads),
+                ..Default::default()
+            },
+            ..small_lm_props(&p)
+        };
+        // Key and value projections only produce n_kv_heads heads of size 16, instead of 4
+        let removed_kv_dims = (4 - n_kv_heads as u64) * 16;
+        let n_parameters = lm_parameter_count(&props) - 2 * 2 * (64 * removed_kv_dims + removed_kv_dims);
+        let mut language_model = LanguageModel::new(props);
+        language_model.eval();
+        assert_eq!(count_parameters(&vs), n_parameters);
+        let input = Tensor::randint(119, &[3, 12], (Kind::Int64, Device::Cpu));
+        let full_output = language_model.forward(input.shallow_clone());
+        assert_eq!(full_output.size(), &[3, 12, 120]);
+
+        let mut cache = TransformerCache::new();
+        let mut outputs = vec![language_model.forward_step(&input.i((.., ..4)), &mut cache)];
+        for i in 4..12 {
+            outputs.push(language_model.forward_step(&input.i((.., i..i + 1)), &mut cache));
+        }
+        assert!(Tensor::cat(&outputs, 1).allclose(&full_output, 1e-4, 1e-4, false));
+    }
+
+    // Cross attention in decoder blocks uses the same grouping
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut block = TransformerDecoderBlock::new(&vs.root(), 64, 4, 0.1, true, PositionalEncoding::Learned, BlockConfig {
+        n_kv_heads: Some(1),
+        ..Default::default()
+    });
+    let output = block.forward((Tensor::randn(&[3, 6, 64], (Kind::Float, Device::Cpu)), Tensor::randn(&[3, 9, 64], (Kind::Float, Device::Cpu))));
+    assert_eq!(output.size(), &[3, 6, 64]);
+}
+
+#[test]
+fn test_multi_head_attention() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    // Cross attention from 32-dim queries over 12-dim keys and 20-dim values
+    let mut cross_attention = MultiHeadAttention::new(MultiHeadAttentionProps {
+        p: &(&vs.root() / "cross"),
+        n_embd: 32,
+        n_head: 4,
+        n_kv_heads: Some(2),
+        kdim: Some(12),
+        vdim: Some(20),
+        dropout: 0.,
+        causal_mask: false,
+        positional_encoding: PositionalEncoding::Learned,
+        output_dropout: 0.,
+    });
+    assert_eq!(count_parameters(&vs), (12 * 16 + 16) + (32 * 32 + 32) + (20 * 16 + 16) + (32 * 32 + 32));
+    let query = Tensor::randn(&[3, 5, 32], (Kind::Float, Device::Cpu));
+    let (key, value) = (Tensor::randn(&[3, 7, 12], (Kind::Float, Device::Cpu)), Tensor::randn(&[3, 7, 20], (Kind::Float, Device::Cpu)));
+    let padding_mask = Tensor::of_slice(&[0, 0, 0, 0, 0, 1, 1]).view([1, 7]).repeat(&[3, 1]);
+    let (output, weights) = cross_attention.forward_attention(&query, &key, &value, Some(&padding_mask), None, true);
+    assert_eq!(output.size(), &[3, 5, 32]);
+    let weights = weights.unwrap();
+    assert_eq!(weights.size(), &[3, 4, 5, 7]);
+    assert!(weights.sum_dim_intlist(&[-1], false, Kind::Float).allclose(&Tensor::ones(&[3, 4, 5], (Kind::Float, Device::Cpu)), 1e-5, 1e-5, false));
+    assert!(weights.i((.., .., .., 5..)).abs().max().double_value(&[]) < 1e-6);
+
+    // An explicit attention mask matches the causal mask
+    let mut causal_attention = MultiHeadAttention::new(MultiHeadAttentionProps {
+        p: &(&vs.root() / "causal"),
+        n_embd: 32,
+        n_head: 4,
+        n_kv_heads: None,
+        kdim: None,
+        vdim: None,
+        dropout: 0.,
+        causal_mask: true,
+        positional_encoding: PositionalEncoding::Rotary,
+        output_dropout: 0.,
+    });
+    let mut masked_attention = MultiHeadAttention::new(MultiHeadAttentionProps {
+        p: &(&vs.root() / "masked"),
+        n_embd: 32,
+        n_head: 4,
+        n_kv_heads: None,
+        kdim: None,
+        vdim: None,
+        dropout: 0.,
+        causal_mask: false,
+        positional_encoding: PositionalEncoding::Rotary,
+        output_dropout: 0.,
+    });
+    masked_attention.copy(&causal_attention).unwrap();
+    let attn_mask = Tensor::ones(&[5, 5], (Kind::Bool, Device::Cpu)).triu(1);
+    let (masked_output, _) = masked_attention.forward_self_attention(&query, None, Some(&attn_mask), false);
+    assert!(masked_output.allclose(&causal_attention.forward(query.shallow_clone()), 1e-5, 1e-5, false));
+
+    // Keys from another sequence aren't rotated or biased, so cross attention is the same as without rotary embeddings or ALiBi
+    let mut unrotated_attention = MultiHeadAttention::new(MultiHeadAttentionProps {
+        p: &(&vs.root() / "unrotated"),
+        n_embd: 32,
+        n_head: 4,
+        n_kv_heads: None,
+        kdim: None,
+        vdim: None,
+        dropout: 0.,
+        causal_mask: false,
+        positional_encoding: PositionalEncoding::Learned,
+        output_dropout: 0.,
+    });
+    unrotated_attention.copy(&masked_attention).unwrap();
+    let context = Tensor::randn(&[3, 7, 32], (Kind::Float, Device::Cpu));
+    assert!(masked_attention.forward_cross(&query, &context, None).allclose(&unrotated_attention.forward_cross(&query, &context, None), 1e-5, 1e-5, false));
+    let mut alibi_attention = MultiHeadAttention::new(MultiHeadAttentionProps {
+        p: &(&vs.root() / "alibi"),
+        n_embd: 32,
+        n_head: 4,
+        n_kv_heads: None,
+        kdim: None,
+        vdim: None,
+        dropout: 0.,
+        causal_mask: false,
+        positional_encoding: PositionalEncoding::ALiBi,
+        output_dropout: 0.,
+    });
+    alibi_attention.copy(&masked_attention).unwrap();
+    assert!(alibi_attention.forward_cross(&query, &context, None).allclose(&unrotated_attention.forward_cross(&query, &context, None), 1e-5, 1e-5, false));
+}
+
+#[test]
+fn test_dropout_config() {
+    fn props<'a>(p: &'a nn::Path<'a>, block: BlockConfig) -> LanguageModelProps<'a> {
+        LanguageModelProps {
+            p,
+            n_embd: 32,
+            n_head: 4,
+            n_layers: 2,
+            vocab_size: 50,
+            positional_encoding: PositionalEncoding::Learned,
+            max_len: 8,
+            dropout: 0.5,
+            block,
+            tie_embeddings: false,
+        }
+    }
+    let vs = nn::VarStore::new(Device::Cpu);
+    let input = Tensor::randint(50, &[3, 8], (Kind::Int64, Device::Cpu));
+
+    // Overriding every component's dropout with 0 makes train mode deterministic
+    let mut language_model = LanguageModel::new(props(&(&vs.root() / "deterministic"), BlockConfig {
+        attention_dropout: Some(0.),
+        residual_dropout: Some(0.),
+        embedding_dropout: Some(0.),
+        ..Default::default()
+    }));
+    assert!(language_model.forward(input.shallow_clone()).equal(&language_model.forward(input.shallow_clone())));
+
+    // Any single component can still add dropout
+    for (i, block) in [
+        BlockConfig { attention_dropout: Some(0.5), residual_dropout: Some(0.), embedding_dropout: Some(0.), ..Default::default() },
+        BlockConfig { attention_dropout: Some(0.), residual_dropout: Some(0.5), embedding_dropout: Some(0.), ..Default::default() },
+        BlockConfig { attention_dropout: Some(0.), residual_dropout: Some(0.), embedding_dropout: Some(0.5), ..Default::default() },
+    ].iter().cloned().enumerate() {
+        let mut language_model = LanguageModel::new(props(&(&vs.root() / i), block));
+        assert!(!language_model.forward(input.shallow_clone()).equal(&language_model.forward(input.shallow_clone())));
+        language_model.eval();
+        assert!(language_model.forward(input.shallow_clone()).equal(&language_model.forward(input.shallow_clone())));
+    }
+
+    // Cross attention in decoder blocks is also disabled in eval mode
+    let mut block = TransformerDecoderBlock::new(&(&vs.root() / "decoder_block"), 32, 4, 0.5, true, PositionalEncoding::Learned, Default::default());
+    block.eval();
+    let (x, encoder_output) = (Tensor::randn(&[3, 6, 32], (Kind::Float, Device::Cpu)), Tensor::randn(&[3, 9, 32], (Kind::Float, Device::Cpu)));
+    let output = block.forward((x.shallow_clone(), encoder_output.shallow_clone()));
+    assert!(output.equal(&block.forward((x, encoder_output))));
+}
@@ -0,0 +1,228 @@
+use rand::{Rng, SeedableRng, rngs::{StdRng, ThreadRng}, thread_rng};
+use tch::{Device, IndexOp, Kind, Tensor};
+
+/// Settings for turning logits into tokens during generation
+#[derive(Debug, Clone)]
+pub struct SamplingConfig {
+    /// Always pick the most likely token, ignoring temperature, top-k and top-p
+    pub greedy: bool,
+    pub temperature: f64,
+    /// Only sample from the k most likely tokens
+    pub top_k: Option<i64>,
+    /// Only sample from the smallest set of tokens whose probabilities add up to p (nucleus sampling)
+    pub top_p: Option<f64>,
+    /// Penalize tokens which already appear in the sequence, 1.0 means no penalty
+    pub repetition_penalty: f64,
+    /// Stop tokens can't be generated until this many tokens have been generated
+    pub min_len: i64,
+    /// The maximum number of tokens to generate
+    pub max_len: i64,
+    /// A sequence stops once it generates any of these tokens
+    pub stop_tokens: Vec<i64>,
+    /// The token used to fill sequences which have already stopped
+    pub pad_token: i64,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            greedy: false,
+            temperature: 1.,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.,
+            min_len: 0,
+            max_len: 100,
+            stop_tokens: vec![],
+            pad_token: 0,
+        }
+    }
+}
+
+/// Samples batches of tokens from logits, using an injectable RNG so generation can be reproduced
+pub struct Sampler<R: Rng> {
+    pub config: SamplingConfig,
+    rng: R,
+}
+
+/// Check settings which would break sampling
+fn validate(config: &SamplingConfig) {
+    assert!(config.greedy || config.temperature > 0., "Temperature ({}) must be positive, use greedy for deterministic sampling!", config.temperature);
+    if let Some(k) = config.top_k {
+        assert!(k >= 1, "Top-k ({}) must be at least 1!", k);
+    }
+    if let Some(p) = config.top_p {
+        assert!(p > 0. && p <= 1., "Top-p ({}) must be in (0, 1]!", p);
+    }
+}
+
+impl Sampler<ThreadRng> {
+    pub fn new(config: SamplingConfig) -> Self {
+        validate(&config);
+        Sampler {
+            config,
+            rng: thread_rng(),
+        }
+    }
+}
+
+impl Sampler<StdRng> {
+    /// Create a sampler with a seeded RNG, which always produces the same samples for the same logits
+    pub fn seeded(config: SamplingConfig, seed: u64) -> Self {
+        validate(&config);
+        Sampler {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl <R: Rng> Sampler<R> {
+    pub fn with_rng(config: SamplingConfig, rng: R) -> Self {
+        validate(&config);
+        Sampler {
+            config,
+            rng,
+        }
+    }
+
+    /// Apply the repetition penalty, min length, temperature, top-k and top-p to a batch of logits
+    /// logits shape: (batch size, vocab size), previous_tokens shape: (batch size, seq len)
+    pub fn process_logits(&self, logits: &Tensor, previous_tokens: &Tensor, n_generated: i64) -> Tensor {
+        let mut logits = logits.to_kind(Kind::Float);
+        // Repetition penalty (as in CTRL), positive logits are divided and negative logits are multiplied
+        if self.config.repetition_penalty != 1. && previous_tokens.size()[1] > 0 {
+            let previous_tokens = previous_tokens.to_kind(Kind::Int64);
+            let previous_logits = logits.gather(1, &previous_tokens, false);
+            let penalized = (&previous_logits * self.config.repetition_penalty).where_self(
+                &previous_logits.lt(0.),
+                &(&previous_logits / self.config.repetition_penalty),
+            );
+            logits = logits.scatter(1, &previous_tokens, &penalized);
+        }
+        // Don't allow stopping before min_len
+        if n_generated < self.config.min_len && !self.config.stop_tokens.is_empty() {
+            let stop_tokens = Tensor::of_slice(&self.config.stop_tokens).to(logits.device());
+            logits = logits.index_fill(1, &stop_tokens, f64::NEG_INFINITY);
+        }
+        if self.config.greedy {
+            return logits;
+        }
+        logits = logits / self.config.temperature;
+        if let Some(k) = self.config.top_k {
+            let k = k.min(logits.size()[1]);
+            let (values, _) = logits.topk(k, -1, true, true);
+            logits = logits.masked_fill(&logits.lt_tensor(&values.i((.., k - 1..k))), f64::NEG_INFINITY);
+        }
+        if let Some(p) = self.config.top_p {
+            let (sorted_logits, indices) = logits.sort(-1, true);
+            let sorted_probs = sorted_logits.softmax(-1, Kind::Float);
+            // Remove tokens once the probability mass before them exceeds p, which always keeps the most likely token
+            let remove = (sorted_probs.cumsum(-1, Kind::Float) - &sorted_probs).gt(p);
+            logits = logits.scatter(1, &indices, &sorted_logits.masked_fill(&remove, f64::NEG_INFINITY));
+        }
+        logits
+    }
+
+    /// Sample the next token for each sequence in the batch
+    /// logits shape: (batch size, vocab size), previous_tokens shape: (batch size, seq len), output shape: (batch size)
+    pub fn sample(&mut self, logits: &Tensor, previous_tokens: &Tensor, n_generated: i64) -> Tensor {
+        let logits = self.process_logits(logits, previous_tokens, n_generated);
+        if self.config.greedy {
+            return logits.argmax(Some(-1), false);
+        }
+        let probs = Vec::<Vec<f64>>::from(&logits.softmax(-1, Kind::Double).to(Device::Cpu));
+        let tokens: Vec<i64> = probs.iter().map(|probs| {
+            let num = self.rng.gen_range((0.)..1.);
+            let mut total = 0.;
+            for (i, val) in probs.iter().enumerate() {
+                total += val;
+                if total > num {return i as i64;}
+            }
+            // Rounding errors, fall back to the last token with any probability
+            probs.iter().rposition(|p| *p > 0.).unwrap_or(0) as i64
+        }).collect();
+        Tensor::of_slice(&tokens).to(logits.device())
+    }
+
+    /// Check which sampled tokens (batch size) are stop tokens, returning a boolean tensor (batch size)
+    pub fn is_stop_token(&self, tokens: &Tensor) -> Tensor {
+        let mut stopped = tokens.zeros_like().to_kind(Kind::Bool);
+        for token in &self.config.stop_tokens {
+            stopped = stopped.logical_or(&tokens.eq(*token));
+        }
+        stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{Device, Kind, Tensor};
+    use super::{Sampler, SamplingConfig};
+
+    #[test]
+    fn test_greedy() {
+        let mut sampler = Sampler::new(SamplingConfig { greedy: true, ..Default::default() });
+        let logits = Tensor::of_slice(&[0.1f32, 3., 0.2, 0.4, 2., 0.1]).view([2, 3]);
+        let previous = Tensor::zeros(&[2, 0], (Kind::Int64, Device::Cpu));
+        assert_eq!(Vec::<i64>::from(sampler.sample(&logits, &previous, 0)), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_top_k_and_top_p() {
+        let logits = Tensor::of_slice(&[1f32, 4., 3., 2.]).view([1, 4]);
+        let previous = Tensor::zeros(&[1, 0], (Kind::Int64, Device::Cpu));
+        // Top-k of 1 is always the argmax
+        let mut sampler = Sampler::seeded(SamplingConfig { top_k: Some(1), ..Default::default() }, 0);
+        for _ in 0..20 {
+            assert_eq!(Vec::<i64>::from(sampler.sample(&logits, &previous, 0)), vec![1]);
+        }
+        // Top-p keeps the smallest set of tokens reaching p
+        let sampler = Sampler::seeded(SamplingConfig { top_p: Some(0.8), ..Default::default() }, 0);
+        let processed = sampler.process_logits(&logits, &previous, 0);
+        let kept = Vec::<bool>::from(processed.isfinite());
+        assert_eq!(kept, vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn test_repetition_penalty_and_min_len() {
+        let sampler = Sampler::new(SamplingConfig {
+            repetition_penalty: 2.,
+            min_len: 2,
+            stop_tokens: vec![3],
+            ..Default::default()
+        });
+        let logits = Tensor::of_slice(&[2f32, -2., 1., 5.]).view([1, 4]);
+        let previous = Tensor::of_slice(&[0i64, 1]).view([1, 2]);
+        let processed = Vec::<f32>::from(sampler.process_logits(&logits, &previous, 0));
+        assert_eq!(&processed[..3], &[1., -4., 1.]);
+        assert_eq!(processed[3], f32::NEG_INFINITY);
+        // Stop tokens are allowed after min_len
+        let processed = Vec::<f32>::from(sampler.process_logits(&logits, &previous, 2));
+        assert_eq!(processed[3], 5.);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_top_k() {
+        Sampler::new(SamplingConfig { top_k: Some(0), ..Default::default() });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_top_p() {
+        Sampler::new(SamplingConfig { top_p: Some(0.), ..Default::default() });
+    }
+
+    #[test]
+    fn test_seeded_reproducible() {
+        let logits = Tensor::randn(&[8, 50], (Kind::Float, Device::Cpu));
+        let previous = Tensor::zeros(&[8, 0], (Kind::Int64, Device::Cpu));
+        let mut sampler1 = Sampler::seeded(SamplingConfig::default(), 42);
+        let mut sampler2 = Sampler::seeded(SamplingConfig::default(), 42);
+        assert_eq!(
+            Vec::<i64>::from(sampler1.sample(&logits, &previous, 0)),
+            Vec::<i64>::from(sampler2.sample(&logits, &previous, 0))
+        );
+    }
+}
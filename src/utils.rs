@@ -1,5 +1,6 @@
 use std::ops::Div;
 
+use crate::modules::Module;
 use crate::other_crates::indicatif::{ProgressBar, ProgressStyle};
 use num::{Float, Zero};
 use rand::{Rng, thread_rng};
@@ -124,12 +125,33 @@ pub fn readable_number(num: i64) -> String {
     num.to_string()
 }
 
+/// Count all parameters in the VarStore, including frozen ones
 pub fn count_parameters(vs: &VarStore) -> u64 {
     vs.trainable_variables().iter().map(|tensor| {
         tensor.size().iter().map(|t| {*t as u64}).product::<u64>()
     }).sum::<u64>()
 }
 
+/// Count the parameters in the VarStore which aren't frozen
+pub fn count_trainable_parameters(vs: &VarStore) -> u64 {
+    vs.trainable_variables().iter().filter(|tensor| tensor.requires_grad()).map(|tensor| {
+        tensor.size().iter().map(|t| {*t as u64}).product::<u64>()
+    }).sum::<u64>()
+}
+
+/// Count the parameters in the VarStore which are frozen
+pub fn count_frozen_parameters(vs: &VarStore) -> u64 {
+    count_parameters(vs) - count_trainable_parameters(vs)
+}
+
+/// Count a single module's parameters, returns (trainable, frozen)
+pub fn count_module_parameters<M: Module>(module: &M) -> (u64, u64) {
+    module.parameters().iter().fold((0, 0), |(trainable, frozen), tensor| {
+        let size = tensor.size().iter().map(|t| {*t as u64}).product::<u64>();
+        if tensor.requires_grad() {(trainable + size, frozen)} else {(trainable, frozen + size)}
+    })
+}
+
 pub struct ExponentialAverage<T: Float> {
     beta: f64,
     moment: f64,